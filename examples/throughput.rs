@@ -0,0 +1,139 @@
+//! Measures how many packets per second the GDM server can take in and answer.
+//!
+//! Every client says hello, then keeps a window of pings in flight and sends a new one for every answer,
+//! so the number of answers is what the server gets through. Start the server with `RATE_LIMIT=false`, then run
+//!
+//!     cargo run --release --example throughput -- [address] [clients] [seconds]
+//!
+//! Clients use separate sockets, so with several `RECV_WORKERS` they're spread over all of them.
+
+use std::{
+    env,
+    io::ErrorKind,
+    net::UdpSocket,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+const HELLO: u8 = 0x3;
+const ACK_HELLO: u8 = 0x4;
+const PING: u8 = 0x0;
+const DISCONNECT: u8 = 0x2;
+
+// pings in flight per client
+const WINDOW: usize = 32;
+
+fn packet(prefix: u8, client_id: i32, key: u32, body: &[u8]) -> Vec<u8> {
+    let mut data = vec![prefix];
+    data.extend_from_slice(&client_id.to_le_bytes());
+    data.extend_from_slice(&key.to_le_bytes());
+    data.extend_from_slice(body);
+    data
+}
+
+fn hello(socket: &UdpSocket, client_id: i32) -> std::io::Result<u32> {
+    let mut buf = [0u8; 4096];
+    for _ in 0..5 {
+        socket.send(&packet(HELLO, client_id, 0, &[]))?;
+
+        // the message of the day can come along with the ack
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while Instant::now() < deadline {
+            match socket.recv(&mut buf) {
+                Ok(len) if len >= 5 && buf[0] == ACK_HELLO => {
+                    return Ok(u32::from_le_bytes(buf[1..5].try_into().unwrap()));
+                }
+                Ok(_) => {}
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => break,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    Err(std::io::Error::new(ErrorKind::TimedOut, "no AckHello"))
+}
+
+fn client(address: String, client_id: i32, stop: Arc<AtomicBool>, answered: Arc<AtomicU64>) -> std::io::Result<()> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.connect(&address)?;
+    socket.set_read_timeout(Some(Duration::from_millis(200)))?;
+
+    let key = hello(&socket, client_id)?;
+    let ping = packet(PING, client_id, key, &[0u8; 20]);
+
+    let mut buf = [0u8; 4096];
+    for _ in 0..WINDOW {
+        socket.send(&ping)?;
+    }
+
+    while !stop.load(Ordering::Relaxed) {
+        match socket.recv(&mut buf) {
+            Ok(len) if len >= 1 && buf[0] == PING => {
+                answered.fetch_add(1, Ordering::Relaxed);
+                socket.send(&ping)?;
+            }
+            Ok(_) => {}
+            // lost somewhere, fill the window up again
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                for _ in 0..WINDOW {
+                    socket.send(&ping)?;
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    socket.send(&packet(DISCONNECT, client_id, key, &[]))?;
+    Ok(())
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let address = args.next().unwrap_or("127.0.0.1:53790".to_string());
+    let clients: i32 = args.next().and_then(|arg| arg.parse().ok()).unwrap_or(8);
+    let seconds: u64 = args.next().and_then(|arg| arg.parse().ok()).unwrap_or(10);
+
+    // a new range of IDs every run, so sessions left over from the last one don't get in the way
+    let first_id = (SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() % 1_000_000) as i32 * 1000;
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let answered = Arc::new(AtomicU64::new(0));
+
+    let handles: Vec<_> = (0..clients)
+        .map(|n| {
+            let (address, stop, answered) = (address.clone(), stop.clone(), answered.clone());
+            thread::spawn(move || {
+                if let Err(e) = client(address, first_id + n, stop, answered) {
+                    eprintln!("client {n}: {e}");
+                }
+            })
+        })
+        .collect();
+
+    // give everyone time to connect before counting
+    thread::sleep(Duration::from_secs(1));
+    let start = answered.load(Ordering::Relaxed);
+    let started = Instant::now();
+
+    for _ in 0..seconds {
+        thread::sleep(Duration::from_secs(1));
+        let total = answered.load(Ordering::Relaxed) - start;
+        println!("{:>8.0} packets/s", total as f64 / started.elapsed().as_secs_f64());
+    }
+
+    stop.store(true, Ordering::Relaxed);
+    for handle in handles {
+        let _ = handle.join();
+    }
+
+    let total = answered.load(Ordering::Relaxed) - start;
+    println!(
+        "{clients} clients, {total} packets answered in {:.1}s, {:.0} packets/s",
+        started.elapsed().as_secs_f64(),
+        total as f64 / started.elapsed().as_secs_f64()
+    );
+}
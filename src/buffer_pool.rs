@@ -0,0 +1,72 @@
+use std::{
+    ops::Deref,
+    sync::{Arc, Mutex},
+};
+
+// largest datagram a client is expected to send, anything longer is cut off
+pub const MAX_DATAGRAM: usize = 4096;
+
+/// Receive buffers that are handed to the packet handlers and come back once they're dropped,
+/// so receiving a packet doesn't allocate or copy it.
+pub struct BufferPool {
+    free: Mutex<Vec<Vec<u8>>>,
+    max_free: usize, // buffers kept around after a burst, the rest are freed
+}
+
+impl BufferPool {
+    pub fn new(max_free: usize) -> Arc<Self> {
+        Arc::new(BufferPool {
+            free: Mutex::new(vec![]),
+            max_free,
+        })
+    }
+
+    pub fn take(self: &Arc<Self>) -> PooledBuffer {
+        let data = self
+            .free
+            .lock()
+            .unwrap()
+            .pop()
+            .unwrap_or_else(|| vec![0u8; MAX_DATAGRAM]);
+
+        PooledBuffer {
+            data,
+            len: 0,
+            pool: self.clone(),
+        }
+    }
+}
+
+pub struct PooledBuffer {
+    data: Vec<u8>, // always MAX_DATAGRAM long, only `len` of it is the packet
+    len: usize,
+    pool: Arc<BufferPool>,
+}
+
+impl PooledBuffer {
+    // the whole buffer, to receive into
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+
+    pub fn set_len(&mut self, len: usize) {
+        self.len = len.min(self.data.len());
+    }
+}
+
+impl Deref for PooledBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        let mut free = self.pool.free.lock().unwrap();
+        if free.len() < self.pool.max_free {
+            free.push(std::mem::take(&mut self.data));
+        }
+    }
+}
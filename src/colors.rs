@@ -0,0 +1,168 @@
+use std::{collections::BTreeMap, io::ErrorKind, path::PathBuf, sync::Arc, time::SystemTime};
+
+use arc_swap::ArcSwap;
+use log::{info, warn};
+use tokio::{fs, sync::Mutex as AsyncMutex};
+
+// GD palette indices in hue order, used when the server animates rainbow colors itself
+const RAINBOW_COLORS: [u8; 12] = [9, 10, 11, 0, 1, 2, 3, 4, 5, 6, 7, 8];
+const PASTEL_COLORS: [u8; 7] = [19, 14, 0, 40, 16, 41, 13];
+
+// how long each color is shown for
+const RAINBOW_STEP_MS: u128 = 100;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ColorProfile {
+    Rainbow,
+    PastelRainbow,
+    Fixed(String), // hex color, always in the #rrggbb form
+}
+
+impl ColorProfile {
+    pub fn parse(mode: &str, hex: Option<&str>) -> Option<Self> {
+        match mode {
+            "rainbow" => Some(ColorProfile::Rainbow),
+            "pastel" => Some(ColorProfile::PastelRainbow),
+            "hex" => normalize_hex(hex?).map(ColorProfile::Fixed),
+            _ => None,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        let hexcolor = match self {
+            ColorProfile::Fixed(hex) => hex.as_str(),
+            _ => "#ffffff",
+        };
+
+        format!(
+            r#"{{"israinbow":{},"israinbowpastel":{},"hexcolor":"{}"}}"#,
+            *self == ColorProfile::Rainbow,
+            *self == ColorProfile::PastelRainbow,
+            hexcolor
+        )
+    }
+
+    /// Palette indices for both colors to show right now, or `None` if the colors aren't animated.
+    pub fn animated_colors(&self, player_id: i32) -> Option<(u8, u8)> {
+        let colors: &[u8] = match self {
+            ColorProfile::Rainbow => &RAINBOW_COLORS,
+            ColorProfile::PastelRainbow => &PASTEL_COLORS,
+            ColorProfile::Fixed(_) => return None,
+        };
+
+        let millis = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or(0);
+
+        // offset by player ID so that everyone isn't the same color at the same time
+        let step = (millis / RAINBOW_STEP_MS + player_id.unsigned_abs() as u128) as usize;
+        // the secondary color runs half a cycle behind, so the two never match
+        Some((colors[step % colors.len()], colors[(step + colors.len() / 2) % colors.len()]))
+    }
+
+    fn serialize(&self) -> &str {
+        match self {
+            ColorProfile::Rainbow => "rainbow",
+            ColorProfile::PastelRainbow => "pastel",
+            ColorProfile::Fixed(hex) => hex.as_str(),
+        }
+    }
+
+    fn deserialize(value: &str) -> Option<Self> {
+        match value {
+            "rainbow" => Some(ColorProfile::Rainbow),
+            "pastel" => Some(ColorProfile::PastelRainbow),
+            hex => normalize_hex(hex).map(ColorProfile::Fixed),
+        }
+    }
+}
+
+fn normalize_hex(hex: &str) -> Option<String> {
+    let hex = hex.trim_start_matches('#');
+    if hex.len() == 6 && hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        Some(format!("#{}", hex.to_ascii_lowercase()))
+    } else {
+        None
+    }
+}
+
+/// Per-player color profiles, stored as a plain text file with `<player id> <rainbow|pastel|#rrggbb>` on each line.
+/// Reading never waits, a change swaps in a new copy once it's saved, so the tick isn't held up by the disk.
+pub struct ColorProfiles {
+    path: PathBuf,
+    profiles: ArcSwap<BTreeMap<i32, ColorProfile>>,
+    saving: AsyncMutex<()>, // one change at a time, so none of them gets lost
+}
+
+impl ColorProfiles {
+    pub async fn load(path: PathBuf) -> anyhow::Result<Self> {
+        let contents = match fs::read_to_string(&path).await {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut profiles = BTreeMap::new();
+        for line in contents.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let parsed = line.split_once(' ').and_then(|(id, profile)| {
+                Some((id.parse::<i32>().ok()?, ColorProfile::deserialize(profile.trim())?))
+            });
+
+            match parsed {
+                Some((id, profile)) => {
+                    profiles.insert(id, profile);
+                }
+                None => warn!("ignoring invalid color profile entry {line:?}"),
+            }
+        }
+
+        info!("loaded {} color profiles from {path:?}", profiles.len());
+        Ok(ColorProfiles {
+            path,
+            profiles: ArcSwap::from_pointee(profiles),
+            saving: AsyncMutex::new(()),
+        })
+    }
+
+    pub fn get(&self, player_id: i32) -> Option<ColorProfile> {
+        self.profiles.load().get(&player_id).cloned()
+    }
+
+    /// All profiles as they are now, for looking up many players at once.
+    pub fn current(&self) -> Arc<BTreeMap<i32, ColorProfile>> {
+        self.profiles.load_full()
+    }
+
+    pub async fn set(&self, player_id: i32, profile: Option<ColorProfile>) -> anyhow::Result<()> {
+        let _saving = self.saving.lock().await;
+
+        let mut profiles = BTreeMap::clone(&self.profiles.load());
+        match profile {
+            Some(profile) => profiles.insert(player_id, profile),
+            None => profiles.remove(&player_id),
+        };
+
+        self.save(&profiles).await?;
+        // only once it's on disk, so a failed write doesn't leave the file and memory disagreeing
+        self.profiles.store(Arc::new(profiles));
+        Ok(())
+    }
+
+    async fn save(&self, profiles: &BTreeMap<i32, ColorProfile>) -> anyhow::Result<()> {
+        let mut contents = String::new();
+        for (id, profile) in profiles.iter() {
+            contents.push_str(&format!("{id} {}\n", profile.serialize()));
+        }
+
+        // written next to the file and moved over it, so a crash halfway through can't leave it cut off
+        let temp = self.path.with_extension("tmp");
+        fs::write(&temp, contents).await?;
+        fs::rename(&temp, &self.path).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,118 @@
+use std::{env, path::PathBuf, str::FromStr, thread, time::Duration};
+
+use log::warn;
+
+// everything is configured through environment variables
+pub struct Config {
+    pub bind_address: String,
+    pub gdm_port: String,
+    pub http_port: String,
+    pub vip_file: PathBuf,
+    pub colors_file: PathBuf,
+    pub icons_dir: PathBuf,
+    pub icon_cache_dir: PathBuf,
+    pub icon_cache_size: u64, // in bytes
+    pub icon_upstreams: Vec<String>, // tried in order, none unless set
+    pub icon_connect_timeout: Duration,
+    pub icon_read_timeout: Duration,
+    pub motd: Option<String>,
+    pub admin_token: Option<String>, // admin endpoints are disabled when unset
+    pub server_rainbow: bool,        // animate rainbow colors ourselves, for clients that can't
+    pub hello_cookies: CookieMode,
+    pub rate_limit: bool,
+    pub rate_limit_messages: u32,  // position packets per second allowed from one client
+    pub rate_limit_ban_after: u32, // dropped packets within 10 seconds before the address is banned
+    pub rate_limit_ban: Duration,  // zero disables bans
+    pub unverified_hellos: u32,    // hellos without a cookie accepted per second from one /24 or /48
+    pub tick_rate: u32,            // position updates sent per second
+    pub batch_mtu: usize,          // largest batched position datagram, in bytes
+    pub interest: bool,            // only send nearby players, for levels with a lot of people
+    pub interest_radius: u32,      // players closer than this are sent every tick
+    // the rest every this many ticks, and only this many closest players are shown at all
+    pub interest_far_interval: u32,
+    pub interest_max_players: usize,
+    pub snapshot_max_age: Duration, // how out of date the data behind the HTTP read endpoints can get
+    pub outbound_queue: usize,         // datagrams waiting to be sent, more than this are dropped
+    pub outbound_per_recipient: usize, // of those, how many can be for one address
+    pub recv_workers: usize,           // sockets receiving on the GDM port, each with its own task
+    pub ping_timeout: Duration,        // sessions that haven't pinged for this long time out, and close after resume_grace
+    pub idle_timeout: Duration,        // players that haven't sent a position for this long are taken off their level, 0 is never
+    pub resume_grace: Duration,        // timed out sessions can be picked up again for this long
+}
+
+// whether a Hello has to prove it came from its source address before a session is created
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CookieMode {
+    Off,      // sessions are created right away for everyone
+    Optional, // clients that send a cookie field go through the exchange, stock GDM doesn't
+    Required, // hellos without a cookie field are dropped, stock GDM can't connect
+}
+
+impl FromStr for CookieMode {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "off" => Ok(CookieMode::Off),
+            "optional" => Ok(CookieMode::Optional),
+            "required" => Ok(CookieMode::Required),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        Config {
+            bind_address: env::var("BIND_ADDRESS").unwrap_or("0.0.0.0".to_string()),
+            gdm_port: env::var("GDM_PORT").unwrap_or("53790".to_string()),
+            http_port: env::var("HTTP_PORT").unwrap_or("53789".to_string()),
+            vip_file: PathBuf::from(env::var("VIP_FILE").unwrap_or("vips.txt".to_string())),
+            colors_file: PathBuf::from(env::var("COLORS_FILE").unwrap_or("colors.txt".to_string())),
+            icons_dir: PathBuf::from(env::var("ICONS_DIR").unwrap_or("static/icons".to_string())),
+            icon_cache_dir: PathBuf::from(env::var("ICON_CACHE_DIR").unwrap_or("cache/icons".to_string())),
+            icon_cache_size: env_or("ICON_CACHE_SIZE_MB", 64u64) * 1024 * 1024,
+            icon_upstreams: env::var("ICON_UPSTREAMS")
+                .unwrap_or_default()
+                .split(',')
+                .map(|url| url.trim().to_string())
+                .filter(|url| !url.is_empty())
+                .collect(),
+            icon_connect_timeout: Duration::from_millis(env_or("ICON_CONNECT_TIMEOUT_MS", 3000)),
+            icon_read_timeout: Duration::from_millis(env_or("ICON_READ_TIMEOUT_MS", 10000)),
+            motd: env::var("MOTD").ok().filter(|motd| !motd.is_empty()),
+            admin_token: env::var("ADMIN_TOKEN").ok().filter(|token| !token.is_empty()),
+            server_rainbow: env_or("SERVER_RAINBOW", false),
+            hello_cookies: env_or("HELLO_COOKIES", CookieMode::Optional),
+            rate_limit: env_or("RATE_LIMIT", true),
+            rate_limit_messages: env_or("RATE_LIMIT_MESSAGES", 60u32).max(1),
+            rate_limit_ban_after: env_or("RATE_LIMIT_BAN_AFTER", 500),
+            rate_limit_ban: Duration::from_secs(env_or("RATE_LIMIT_BAN_SECS", 0)),
+            unverified_hellos: env_or("UNVERIFIED_HELLOS", 2u32).max(1),
+            tick_rate: env_or("TICK_RATE", 30u32).clamp(1, 120),
+            // has to fit at least one player
+            batch_mtu: env_or("BATCH_MTU", 1200usize).clamp(128, 65507),
+            interest: env_or("INTEREST", false),
+            interest_radius: env_or("INTEREST_RADIUS", 1500),
+            interest_far_interval: env_or("INTEREST_FAR_INTERVAL", 5u32).max(1),
+            interest_max_players: env_or("INTEREST_MAX_PLAYERS", 50usize).max(1),
+            snapshot_max_age: Duration::from_millis(env_or("SNAPSHOT_MAX_AGE_MS", 1000u64).max(10)),
+            outbound_queue: env_or("OUTBOUND_QUEUE", 4096usize).max(1),
+            outbound_per_recipient: env_or("OUTBOUND_PER_RECIPIENT", 256usize).max(1),
+            recv_workers: env_or("RECV_WORKERS", thread::available_parallelism().map_or(1, |cores| cores.get())).max(1),
+            ping_timeout: Duration::from_secs(env_or("PING_TIMEOUT_SECS", 60u64).max(1)),
+            idle_timeout: Duration::from_secs(env_or("IDLE_TIMEOUT_SECS", 600)),
+            resume_grace: Duration::from_secs(env_or("RESUME_GRACE_SECS", 30)),
+        }
+    }
+}
+
+fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    match env::var(name) {
+        Ok(value) => value.parse().unwrap_or_else(|_| {
+            warn!("invalid value for {name}: {value:?}, using the default");
+            default
+        }),
+        Err(_) => default,
+    }
+}
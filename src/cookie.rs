@@ -0,0 +1,109 @@
+use std::{
+    net::SocketAddr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use sha2::Sha256;
+
+use crate::protocol::{Cookie, COOKIE_LEN};
+
+// cookies are bound to a time window, and the previous window is still accepted,
+// so a cookie lives between one and two windows
+const COOKIE_WINDOW_SECS: u64 = 30;
+
+/// Issues and checks `Hello` cookies. Nothing is stored per client, a cookie is just a MAC over
+/// the address, the client ID and the time window, so only whoever receives packets at that address can answer it.
+pub struct CookieJar {
+    secret: [u8; 32],
+}
+
+impl CookieJar {
+    pub fn new() -> Self {
+        // a new secret on every start, clients just ask for a new cookie after a restart
+        CookieJar {
+            secret: rand::thread_rng().gen(),
+        }
+    }
+
+    pub fn issue(&self, address: SocketAddr, client_id: i32) -> Cookie {
+        self.compute(address, client_id, current_window())
+    }
+
+    pub fn verify(&self, address: SocketAddr, client_id: i32, cookie: &Cookie) -> bool {
+        self.verify_in(address, client_id, cookie, current_window())
+    }
+
+    fn verify_in(&self, address: SocketAddr, client_id: i32, cookie: &Cookie, window: u64) -> bool {
+        // verify_truncated_left compares in constant time
+        [window, window.saturating_sub(1)]
+            .iter()
+            .any(|window| self.mac(address, client_id, *window).verify_truncated_left(cookie).is_ok())
+    }
+
+    fn compute(&self, address: SocketAddr, client_id: i32, window: u64) -> Cookie {
+        let tag = self.mac(address, client_id, window).finalize().into_bytes();
+        let mut cookie = [0u8; COOKIE_LEN];
+        cookie.copy_from_slice(&tag[..COOKIE_LEN]);
+        cookie
+    }
+
+    fn mac(&self, address: SocketAddr, client_id: i32, window: u64) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret).expect("hmac takes keys of any size");
+        mac.update(address.to_string().as_bytes());
+        mac.update(&client_id.to_le_bytes());
+        mac.update(&window.to_le_bytes());
+        mac
+    }
+}
+
+impl Default for CookieJar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn current_window() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs() / COOKIE_WINDOW_SECS)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(last: u8) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, last], 1234))
+    }
+
+    #[test]
+    fn issued_cookies_verify() {
+        let jar = CookieJar::new();
+        let cookie = jar.issue(address(1), 7);
+        assert!(jar.verify(address(1), 7, &cookie));
+    }
+
+    #[test]
+    fn cookies_only_work_for_their_address_and_client() {
+        let jar = CookieJar::new();
+        let cookie = jar.compute(address(1), 7, 100);
+
+        assert!(jar.verify_in(address(1), 7, &cookie, 100));
+        assert!(!jar.verify_in(address(2), 7, &cookie, 100));
+        assert!(!jar.verify_in(SocketAddr::from(([10, 0, 0, 1], 4321)), 7, &cookie, 100));
+        assert!(!jar.verify_in(address(1), 8, &cookie, 100));
+        assert!(!CookieJar::new().verify_in(address(1), 7, &cookie, 100));
+    }
+
+    #[test]
+    fn cookies_last_into_the_next_window() {
+        let jar = CookieJar::new();
+        let cookie = jar.compute(address(1), 7, 100);
+
+        assert!(jar.verify_in(address(1), 7, &cookie, 101));
+        assert!(!jar.verify_in(address(1), 7, &cookie, 102));
+    }
+}
@@ -0,0 +1,295 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::protocol::{self, PlayerDeltas, PlayerPosition, PositionDelta, DELTA_ALL_FIELDS};
+
+// updates remembered per recipient while waiting for an ack, about a second at the default tick rate.
+// once the acked one falls out of this, the recipient gets everything in full again
+const HISTORY_LEN: usize = 32;
+
+#[derive(Default)]
+struct Recipient {
+    sequence: u32,
+    sent: VecDeque<(u32, HashMap<i32, PlayerPosition>)>, // sequence : positions in that update
+    acked: Option<(u32, HashMap<i32, PlayerPosition>)>,
+}
+
+/// Bookkeeping for clients with `FEATURE_DELTA_POSITIONS`. Updates are deltas against the last update
+/// the recipient acknowledged, so a lost datagram just means the next delta is against an older baseline.
+#[derive(Default)]
+pub struct DeltaTracker {
+    recipients: HashMap<i32, Recipient>,
+    incoming: HashMap<i32, u32>, // client_id : sequence of the newest Message applied from it
+}
+
+impl DeltaTracker {
+    /// Returns false for a `Message` that's older than one already applied.
+    pub fn accept_incoming(&mut self, client_id: i32, sequence: u32) -> bool {
+        match self.incoming.get(&client_id) {
+            Some(last) if !is_newer(sequence, *last) => false,
+            _ => {
+                self.incoming.insert(client_id, sequence);
+                true
+            }
+        }
+    }
+
+    pub fn ack(&mut self, client_id: i32, sequence: u32) {
+        let Some(recipient) = self.recipients.get_mut(&client_id) else {
+            return;
+        };
+
+        // late acks don't move the baseline back
+        if let Some((acked, _)) = &recipient.acked {
+            if !is_newer(sequence, *acked) {
+                return;
+            }
+        }
+
+        while let Some((sent, positions)) = recipient.sent.pop_front() {
+            if sent == sequence {
+                recipient.acked = Some((sent, positions));
+                break;
+            }
+
+            // an ack for something that was never sent, keep the rest
+            if is_newer(sent, sequence) {
+                recipient.sent.push_front((sent, positions));
+                break;
+            }
+        }
+    }
+
+    /// Encodes the positions of everyone `client_id` can see as the next update, split into datagrams
+    /// of at most `mtu` bytes. Nothing is sent if nothing changed since the acked update.
+    pub fn encode(&mut self, client_id: i32, mut positions: HashMap<i32, PlayerPosition>, mtu: usize) -> Vec<Vec<u8>> {
+        let recipient = self.recipients.entry(client_id).or_default();
+
+        let (baseline, base) = match &recipient.acked {
+            Some((sequence, positions)) => (*sequence, Some(positions)),
+            None => (0, None),
+        };
+
+        // every visible player is listed, so the client knows who's gone. players that aren't in the baseline
+        // are sent in full, and the ones that didn't change are just their ID and an empty mask
+        let deltas: Vec<PositionDelta> = positions
+            .iter()
+            .map(|(player_id, position)| {
+                let changed = match base.and_then(|base| base.get(player_id)) {
+                    Some(old) => position.changed_fields(old),
+                    None => DELTA_ALL_FIELDS,
+                };
+
+                PositionDelta {
+                    player_id: *player_id,
+                    changed,
+                    position: position.clone(),
+                }
+            })
+            .collect();
+
+        // the client could still be showing an unacked update, then it has to be told to go back to the baseline
+        let unchanged = deltas.iter().all(|delta| delta.changed == 0)
+            && base.is_some_and(|base| base.len() == positions.len());
+        if unchanged && recipient.sent.is_empty() {
+            return vec![];
+        }
+
+        // 0 means no baseline, so it's skipped when wrapping around
+        recipient.sequence = recipient.sequence.wrapping_add(1).max(1);
+        let sequence = recipient.sequence;
+
+        let mut parts: Vec<Vec<PositionDelta>> = vec![vec![]];
+        let mut size = PlayerDeltas::HEADER_LEN;
+        for delta in deltas {
+            let len = delta.encoded_len();
+            match parts.last_mut() {
+                Some(part) if size + len <= mtu && part.len() < u8::MAX as usize => {
+                    size += len;
+                    part.push(delta);
+                }
+                _ => {
+                    size = PlayerDeltas::HEADER_LEN + len;
+                    parts.push(vec![delta]);
+                }
+            }
+        }
+
+        // thousands of players in one level won't fit in 255 parts, the rest waits for the next update.
+        // they're left out of the remembered positions too, so they count as new then
+        if parts.len() > u8::MAX as usize {
+            for delta in parts.split_off(u8::MAX as usize).iter().flatten() {
+                positions.remove(&delta.player_id);
+            }
+        }
+        let part_count = parts.len() as u8;
+
+        let datagrams = parts
+            .into_iter()
+            .enumerate()
+            .map(|(part, deltas)| {
+                protocol::encode(&PlayerDeltas {
+                    sequence,
+                    baseline,
+                    part: part as u8,
+                    parts: part_count,
+                    deltas,
+                })
+            })
+            .collect();
+
+        recipient.sent.push_back((sequence, positions));
+        if recipient.sent.len() > HISTORY_LEN {
+            recipient.sent.pop_front();
+        }
+
+        datagrams
+    }
+
+    /// Position of a player in the newest update sent to `client_id`, for players that aren't refreshed every tick.
+    pub fn last_sent(&self, client_id: i32, player_id: i32) -> Option<&PlayerPosition> {
+        let recipient = self.recipients.get(&client_id)?;
+        recipient
+            .sent
+            .back()
+            .or(recipient.acked.as_ref())
+            .and_then(|(_, positions)| positions.get(&player_id))
+    }
+
+    /// Drops everything about a client, the next update it gets is sent in full.
+    pub fn forget(&mut self, client_id: i32) {
+        self.recipients.remove(&client_id);
+        self.incoming.remove(&client_id);
+    }
+
+    pub fn retain(&mut self, mut keep: impl FnMut(i32) -> bool) {
+        self.recipients.retain(|client_id, _| keep(*client_id));
+        self.incoming.retain(|client_id, _| keep(*client_id));
+    }
+}
+
+/// What clients without `FEATURE_DELTA_POSITIONS` were last sent of every player. They get full positions,
+/// so players that didn't move since are left out, apart from a refresh now and then in case a datagram got lost.
+#[derive(Default)]
+pub struct SentPositions {
+    recipients: HashMap<i32, HashMap<i32, (PlayerPosition, u64)>>, // recipient : player : position, tick it was sent
+}
+
+impl SentPositions {
+    /// Whether `player_id` has to be sent to `recipient` this tick. If so, it's remembered as sent.
+    pub fn needs(&mut self, recipient: i32, player_id: i32, position: &PlayerPosition, tick: u64, refresh: u64) -> bool {
+        let sent = self.recipients.entry(recipient).or_default();
+        match sent.get(&player_id) {
+            Some((last, at)) if last == position && tick.wrapping_sub(*at) < refresh => false,
+            _ => {
+                sent.insert(player_id, (position.clone(), tick));
+                true
+            }
+        }
+    }
+
+    /// The recipient was told the player is gone, so the player is new to it again.
+    pub fn hidden(&mut self, recipient: i32, player_id: i32) {
+        if let Some(sent) = self.recipients.get_mut(&recipient) {
+            sent.remove(&player_id);
+        }
+    }
+
+    /// Called when a client leaves its level or starts over, it's new to everyone and everyone is new to it.
+    pub fn forget(&mut self, client_id: i32) {
+        self.recipients.remove(&client_id);
+        for sent in self.recipients.values_mut() {
+            sent.remove(&client_id);
+        }
+    }
+
+    pub fn retain(&mut self, mut keep: impl FnMut(i32) -> bool) {
+        self.recipients.retain(|client_id, _| keep(*client_id));
+    }
+}
+
+// sequence numbers wrap around, so "newer" means less than half the range ahead
+fn is_newer(sequence: u32, than: u32) -> bool {
+    (sequence.wrapping_sub(than) as i32) > 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::decode;
+
+    fn at(x: i32) -> PlayerPosition {
+        PlayerPosition {
+            p1_pos: (x, 0),
+            ..Default::default()
+        }
+    }
+
+    fn send(tracker: &mut DeltaTracker, positions: &[(i32, i32)]) -> PlayerDeltas {
+        let positions = positions.iter().map(|(player_id, x)| (*player_id, at(*x))).collect();
+        let mut datagrams = tracker.encode(1, positions, 1200);
+        assert_eq!(datagrams.len(), 1);
+        decode(&datagrams.remove(0)).unwrap()
+    }
+
+    #[test]
+    fn older_messages_are_dropped() {
+        let mut tracker = DeltaTracker::default();
+        assert!(tracker.accept_incoming(1, 5));
+        assert!(!tracker.accept_incoming(1, 4));
+        assert!(!tracker.accept_incoming(1, 5));
+        assert!(tracker.accept_incoming(1, 6));
+
+        // across the wrap around
+        assert!(tracker.accept_incoming(2, u32::MAX));
+        assert!(tracker.accept_incoming(2, 1));
+        assert!(!tracker.accept_incoming(2, u32::MAX));
+    }
+
+    #[test]
+    fn deltas_are_against_the_last_acked_update() {
+        let mut tracker = DeltaTracker::default();
+
+        let first = send(&mut tracker, &[(2, 10), (3, 10)]);
+        assert_eq!((first.sequence, first.baseline), (1, 0));
+        assert!(first.deltas.iter().all(|delta| delta.changed == DELTA_ALL_FIELDS));
+        tracker.ack(1, first.sequence);
+
+        // the second one is lost, so the third is still against the first
+        let second = send(&mut tracker, &[(2, 20), (3, 10)]);
+        assert_eq!(second.baseline, 1);
+        let third = send(&mut tracker, &[(2, 30), (3, 10)]);
+        assert_eq!((third.sequence, third.baseline), (3, 1));
+
+        let changed: HashMap<i32, u32> = third.deltas.iter().map(|delta| (delta.player_id, delta.changed)).collect();
+        assert_ne!(changed[&2], 0);
+        assert_eq!(changed[&3], 0);
+
+        // a late ack doesn't move the baseline back
+        tracker.ack(1, third.sequence);
+        tracker.ack(1, second.sequence);
+        assert_eq!(send(&mut tracker, &[(2, 40), (3, 10)]).baseline, 3);
+    }
+
+    #[test]
+    fn without_an_ack_everything_is_sent_in_full() {
+        let mut tracker = DeltaTracker::default();
+        send(&mut tracker, &[(2, 10)]);
+
+        let second = send(&mut tracker, &[(2, 10)]);
+        assert_eq!(second.baseline, 0);
+        assert_eq!(second.deltas[0].changed, DELTA_ALL_FIELDS);
+    }
+
+    #[test]
+    fn nothing_is_sent_once_the_client_is_up_to_date() {
+        let mut tracker = DeltaTracker::default();
+        let first = send(&mut tracker, &[(2, 10)]);
+        tracker.ack(1, first.sequence);
+
+        assert!(tracker.encode(1, HashMap::from([(2, at(10))]), 1200).is_empty());
+
+        // but a player leaving is news
+        let left = send(&mut tracker, &[]);
+        assert!(left.deltas.is_empty());
+    }
+}
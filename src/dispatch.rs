@@ -0,0 +1,104 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use log::{debug, warn};
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+use crate::{
+    buffer_pool::PooledBuffer, gdm_server::handle_packet, protocol::ClientHeader, state::State, stats::Stats,
+};
+
+// packets waiting for one client's worker, anything past this is dropped like the network would
+const QUEUE_LEN: usize = 64;
+// workers that haven't been given anything for this long are stopped
+const WORKER_IDLE: Duration = Duration::from_secs(30);
+
+type Packet = (PooledBuffer, SocketAddr);
+
+struct Worker {
+    queue: mpsc::Sender<Packet>,
+    last_used: Instant,
+}
+
+/// Hands packets to one worker task per client, so packets from the same client are handled
+/// one at a time in the order they arrived, while different clients are still handled in parallel.
+pub struct Dispatcher {
+    state: Arc<State>,
+    // keyed by address too, so a packet with someone else's ID can't fill up their queue
+    workers: HashMap<(SocketAddr, i32), Worker>,
+    last_prune: Instant,
+}
+
+impl Dispatcher {
+    pub fn new(state: Arc<State>) -> Self {
+        Dispatcher {
+            state,
+            workers: HashMap::new(),
+            last_prune: Instant::now(),
+        }
+    }
+
+    pub fn dispatch(&mut self, data: PooledBuffer, address: SocketAddr) {
+        let now = Instant::now();
+        if now.duration_since(self.last_prune) >= WORKER_IDLE {
+            self.prune(now);
+        }
+
+        // nothing to order by without a header, those fail to decode anyway
+        let Ok(header) = ClientHeader::peek(&data) else {
+            let state = self.state.clone();
+            tokio::spawn(async move {
+                if let Err(e) = handle_packet(state, &data, address).await {
+                    warn!("remote err from {address}: {e}");
+                }
+            });
+            return;
+        };
+
+        let worker = self
+            .workers
+            .entry((address, header.client_id))
+            .or_insert_with(|| spawn_worker(self.state.clone()));
+        worker.last_used = now;
+
+        match worker.queue.try_send((data, address)) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                debug!("queue for {} at {address} is full, dropping a packet", header.client_id);
+                Stats::bump(&self.state.stats.queue_full);
+            }
+            // the worker panicked, the next packet gets a new one
+            Err(TrySendError::Closed(_)) => {
+                self.workers.remove(&(address, header.client_id));
+            }
+        }
+    }
+
+    // dropping the sender lets the worker finish whatever is left in its queue and exit
+    fn prune(&mut self, now: Instant) {
+        self.last_prune = now;
+        self.workers
+            .retain(|_, worker| now.duration_since(worker.last_used) < WORKER_IDLE);
+    }
+}
+
+fn spawn_worker(state: Arc<State>) -> Worker {
+    let (queue, mut packets) = mpsc::channel::<Packet>(QUEUE_LEN);
+
+    tokio::spawn(async move {
+        while let Some((data, address)) = packets.recv().await {
+            if let Err(e) = handle_packet(state.clone(), &data, address).await {
+                warn!("remote err from {address}: {e}");
+            }
+        }
+    });
+
+    Worker {
+        queue,
+        last_used: Instant::now(),
+    }
+}
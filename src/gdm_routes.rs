@@ -1,16 +1,23 @@
-use http_body_util::{BodyExt, Empty};
-use hyper::{body::Bytes, Request};
-use hyper_util::rt::TokioIo;
-use log::{debug, error, warn};
+use std::collections::BTreeMap;
+
+use log::{debug, error, info, warn};
 use roa::{
-    http::StatusCode,
+    http::{HeaderValue, StatusCode},
     preload::*,
-    router::{get, Router},
+    router::{get, post, Router},
     status, Context,
 };
-use tokio::{fs::File, net::TcpStream};
+use tokio::fs::File;
 
-use crate::state::TSState;
+use crate::{
+    colors::ColorProfile,
+    icon_cache::IconCache,
+    icons::{self, Form, IconRequest},
+    protocol::ServerDataKind,
+    state::TSState,
+    upstream,
+    util::json_escape,
+};
 
 pub async fn version(context: &mut Context<TSState>) -> roa::Result {
     let file = File::open("static/update.version").await?;
@@ -19,20 +26,121 @@ pub async fn version(context: &mut Context<TSState>) -> roa::Result {
 }
 
 pub async fn is_vip(context: &mut Context<TSState>) -> roa::Result {
+    let id = &*context.must_query("id")?;
     if cfg!(debug_assertions) {
-        let id = &*context.must_query("id")?;
         let cv = context.query("cv");
         let pass = context.query("pass");
 
         debug!("/vip id={id:?}, cv={cv:?}, pass={pass:?}");
     }
 
-    context.write("true");
+    let id = id.parse::<i32>().map_err(|_| status!(StatusCode::BAD_REQUEST))?;
+    let is_vip = context.vips.lock().await.is_vip(id);
+
+    context.write(if is_vip { "true" } else { "false" });
+    Ok(())
+}
+
+// admin endpoints expect the token from ADMIN_TOKEN in the X-Admin-Token header
+async fn is_admin(context: &Context<TSState>) -> bool {
+    let token = context
+        .req
+        .headers
+        .get("x-admin-token")
+        .and_then(|value| value.to_str().ok());
+
+    matches!((&context.config.admin_token, token), (Some(expected), Some(token)) if tokens_match(expected, token))
+}
+
+// compares every byte no matter where the first difference is, so the time taken doesn't give the token away
+fn tokens_match(expected: &str, token: &str) -> bool {
+    expected.len() == token.len()
+        && expected
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
+
+async fn check_admin(context: &Context<TSState>) -> roa::Result {
+    if is_admin(context).await {
+        Ok(())
+    } else {
+        Err(status!(StatusCode::FORBIDDEN))
+    }
+}
+
+pub async fn admin_vip(context: &mut Context<TSState>) -> roa::Result {
+    check_admin(context).await?;
+
+    let id = context
+        .must_query("id")?
+        .parse::<i32>()
+        .map_err(|_| status!(StatusCode::BAD_REQUEST))?;
+    let action = context.must_query("action")?.to_string();
+
+    let mut vips = context.vips.lock().await;
+    let result = match action.as_str() {
+        "grant" => vips.grant(id).await,
+        "revoke" => vips.revoke(id).await,
+        _ => return Err(status!(StatusCode::BAD_REQUEST)),
+    };
+    drop(vips);
+
+    if let Err(e) = result {
+        error!("failed to save the VIP list: {e}");
+        return Err(status!(StatusCode::INTERNAL_SERVER_ERROR));
+    }
+
+    context.write("ok");
     Ok(())
 }
 
 pub async fn is_rainbow(context: &mut Context<TSState>) -> roa::Result {
-    context.write(r##"{"israinbow":false,"israinbowpastel":false,"hexcolor":"#ffffff"}"##);
+    let id = context.query("id").and_then(|id| id.parse::<i32>().ok());
+
+    let profile = id.and_then(|id| context.colors.get(id)).map(|profile| profile.to_json());
+
+    context.write(profile.unwrap_or_else(|| {
+        r##"{"israinbow":false,"israinbowpastel":false,"hexcolor":"#ffffff"}"##.to_string()
+    }));
+    Ok(())
+}
+
+// players can change their own colors with their session key if they're VIP, admins can change anyone's
+pub async fn set_color(context: &mut Context<TSState>) -> roa::Result {
+    let id = context
+        .must_query("id")?
+        .parse::<i32>()
+        .map_err(|_| status!(StatusCode::BAD_REQUEST))?;
+    let key = context.query("key").and_then(|key| key.parse::<u32>().ok());
+    let mode = context.must_query("mode")?.to_string();
+    let hex = context.query("hex").map(|hex| hex.to_string());
+
+    let profile = match mode.as_str() {
+        "none" => None,
+        mode => Some(
+            ColorProfile::parse(mode, hex.as_deref()).ok_or(status!(StatusCode::BAD_REQUEST))?,
+        ),
+    };
+
+    let is_admin = is_admin(context).await;
+
+    if !is_admin {
+        let session_key = context.sessions.lock().unwrap().clients.get(&id).map(|session| session.key);
+        if key.is_none() || session_key != key || !context.vips.lock().await.is_vip(id) {
+            return Err(status!(StatusCode::FORBIDDEN));
+        }
+    }
+
+    let result = context.colors.set(id, profile).await;
+
+    if let Err(e) = result {
+        error!("failed to save color profiles: {e}");
+        return Err(status!(StatusCode::INTERNAL_SERVER_ERROR));
+    }
+
+    context.write("ok");
     Ok(())
 }
 
@@ -60,45 +168,162 @@ pub async fn get_icon(context: &mut Context<TSState>) -> roa::Result {
 
     debug!("getIcon.php form={form}, col1={col1}, col2={col2}, icon={icon}, id={id}, glow={glow}, cubeID={cube_id}");
 
-    let redirect_url = format!("http://95.111.251.138/gdm/getIcon.php?form={form}&col1={col1}&col2={col2}&icon={icon}&id={id}&glow={glow}&cubeID={cube_id}");
-    let redirect_url = redirect_url.parse::<hyper::Uri>()?;
-    let host = redirect_url
-        .host()
-        .ok_or(status!(StatusCode::BAD_REQUEST))?;
+    let request = IconRequest {
+        form: Form::parse(form).ok_or(status!(StatusCode::BAD_REQUEST))?,
+        icon: icon.parse().map_err(|_| status!(StatusCode::BAD_REQUEST))?,
+        cube: cube_id.parse().map_err(|_| status!(StatusCode::BAD_REQUEST))?,
+        col1: col1.parse().map_err(|_| status!(StatusCode::BAD_REQUEST))?,
+        col2: col2.parse().map_err(|_| status!(StatusCode::BAD_REQUEST))?,
+        glow: glow != "0" && glow != "false",
+    };
 
-    let port = redirect_url.port_u16().unwrap_or(80);
+    let query = upstream::query_string(&[
+        ("form", form),
+        ("col1", col1),
+        ("col2", col2),
+        ("icon", icon),
+        ("id", id),
+        ("glow", glow),
+        ("cubeID", cube_id),
+    ]);
+    let key = IconCache::key(&request);
 
-    let address = format!("{host}:{port}");
-    let stream = TcpStream::connect(address).await?;
-    let io = TokioIo::new(stream);
+    let if_none_match = context
+        .req
+        .headers
+        .get(hyper::header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_owned());
 
-    let (mut sender, conn) = hyper::client::conn::http1::handshake(io).await?;
-    tokio::task::spawn(async move {
-        if let Err(err) = conn.await {
-            warn!("Connection failed: {:?}", err);
+    let icon_sheets = context.icon_sheets.clone();
+    let icon_cache = context.icon_cache.clone();
+    let icon_upstreams = context.icon_upstreams.clone();
+
+    if let Some(tags) = if_none_match {
+        if let Some(etag) = icon_cache.etag(&key).await {
+            if tags.split(',').any(|tag| tag.trim() == etag || tag.trim() == "*") {
+                context.resp.status = StatusCode::NOT_MODIFIED;
+                if let Ok(etag) = HeaderValue::from_str(&etag) {
+                    context.resp.headers.insert(hyper::header::ETAG, etag);
+                }
+                return Ok(());
+            }
         }
-    });
-
-    let authority = redirect_url.authority().unwrap().clone();
-    let req = Request::builder()
-        .uri(redirect_url)
-        .header(hyper::header::HOST, authority.as_str())
-        .body(Empty::<Bytes>::new())?;
-
-    let mut res = sender.send_request(req).await?;
-    let status = res.status();
-    if !status.is_success() {
-        error!("GDM getIcon api returned error: {status:?}");
-        return Err(status!(StatusCode::INTERNAL_SERVER_ERROR));
     }
 
-    while let Some(next) = res.frame().await {
-        let frame = next?;
-        if let Some(chunk) = frame.data_ref() {
-            context.write(chunk.to_owned());
+    if let Some((png, etag)) = icon_cache.get(&key).await {
+        return write_icon(context, png, Some(etag));
+    }
+
+    let rendered = tokio::task::spawn_blocking(move || icon_sheets.render(&request)).await?;
+
+    let png = match rendered {
+        Ok(Some(png)) => png,
+        Ok(None) => {
+            debug!("no sprites for the icon, asking the upstream");
+            match icon_upstreams.fetch(&query).await {
+                Some(png) => png,
+                None => {
+                    // not cached, so the real icon shows up once the sprites or an upstream are there
+                    if icon_upstreams.is_empty() {
+                        debug!("no sprites and no icon upstreams, serving a placeholder");
+                    } else {
+                        warn!("no icon upstream could provide the icon, serving a placeholder");
+                    }
+                    let placeholder = icons::placeholder().map_err(|e| {
+                        error!("failed to render the placeholder icon: {e}");
+                        status!(StatusCode::INTERNAL_SERVER_ERROR)
+                    })?;
+                    return write_icon(context, placeholder, None);
+                }
+            }
+        }
+        Err(e) => {
+            error!("failed to render icon: {e}");
+            return Err(status!(StatusCode::INTERNAL_SERVER_ERROR));
+        }
+    };
+
+    let etag = match icon_cache.put(&key, &png).await {
+        Ok(etag) => Some(etag),
+        Err(e) => {
+            warn!("failed to cache icon: {e}");
+            None
         }
+    };
+
+    write_icon(context, png, etag)
+}
+
+fn write_icon(context: &mut Context<TSState>, png: Vec<u8>, etag: Option<String>) -> roa::Result {
+    context
+        .resp
+        .headers
+        .insert(hyper::header::CONTENT_TYPE, HeaderValue::from_static("image/png"));
+
+    if let Some(etag) = etag.and_then(|etag| HeaderValue::from_str(&etag).ok()) {
+        context.resp.headers.insert(hyper::header::ETAG, etag);
     }
 
+    context.write(png);
+    Ok(())
+}
+
+pub async fn admin_announce(context: &mut Context<TSState>) -> roa::Result {
+    check_admin(context).await?;
+    let message = context.must_query("message")?.to_string();
+
+    let clients: Vec<i32> = context.sessions.lock().unwrap().clients.keys().copied().collect();
+    let sent = context
+        .broadcast_server_data(&clients, ServerDataKind::Announcement, message)
+        .await;
+
+    info!("announcement sent to {sent} players");
+    context.write(format!("sent to {sent} players"));
+    Ok(())
+}
+
+// empty message clears the MOTD
+pub async fn admin_motd(context: &mut Context<TSState>) -> roa::Result {
+    check_admin(context).await?;
+    let message = context.must_query("message")?.to_string();
+
+    *context.motd.lock().unwrap() = Some(message).filter(|message| !message.is_empty());
+
+    context.write("ok");
+    Ok(())
+}
+
+// shown to everyone on the level right away and to everyone joining it later, empty message clears it
+pub async fn admin_notice(context: &mut Context<TSState>) -> roa::Result {
+    check_admin(context).await?;
+    let level_id = context
+        .must_query("level")?
+        .parse::<i32>()
+        .map_err(|_| status!(StatusCode::BAD_REQUEST))?;
+    let message = context.must_query("message")?.to_string();
+
+    if message.is_empty() {
+        context.level_notices.lock().unwrap().remove(&level_id);
+        context.write("ok");
+        return Ok(());
+    }
+
+    context.level_notices.lock().unwrap().insert(level_id, message.clone());
+    let clients = context.players_in_level(level_id);
+    let sent = context
+        .broadcast_server_data(&clients, ServerDataKind::LevelNotice, message)
+        .await;
+
+    context.write(format!("sent to {sent} players"));
+    Ok(())
+}
+
+pub async fn admin_stats(context: &mut Context<TSState>) -> roa::Result {
+    check_admin(context).await?;
+
+    let stats = context.stats.to_json();
+    context.write(stats);
     Ok(())
 }
 
@@ -106,15 +331,44 @@ pub async fn lobbies(context: &mut Context<TSState>) -> roa::Result {
     let fname = &*context.must_param("file")?;
     let lobby_id = fname.split_once(".json").map(|(x, _)| x).unwrap_or("0");
 
+    // served from the published snapshot, so polling this doesn't touch the game state at all
+    let snapshot = context.snapshot.load();
+
     if lobby_id != "0" {
-        return Err(status!(StatusCode::BAD_REQUEST));
+        let code = lobby_id.parse::<u16>().map_err(|_| status!(StatusCode::BAD_REQUEST))?;
+        let room = code as i16;
+
+        let lobby = snapshot.lobbies.get(&code).ok_or(status!(StatusCode::NOT_FOUND))?;
+
+        let levels: Vec<_> = snapshot.levels.iter().filter(|((_, r), _)| *r == room).map(|((id, _), players)| {
+            format!(r#""{}":{{"Players":{}}}"#, id, players)
+        }).collect();
+
+        context.write(format!(
+            r#"{{"name":"{}","owner":{},"levels":{{{}}}}}"#,
+            json_escape(&lobby.name),
+            lobby.owner,
+            levels.join(",")
+        ));
+
+        return Ok(());
+    }
+
+    // players in the public room are reported as usual, private rooms are listed separately
+    let mut by_level: BTreeMap<i32, (usize, BTreeMap<i16, usize>)> = BTreeMap::new();
+    for ((level_id, room), players) in snapshot.levels.iter() {
+        let entry = by_level.entry(*level_id).or_default();
+        if *room == 0 {
+            entry.0 += players;
+        } else {
+            entry.1.insert(*room, *players);
+        }
     }
 
-    let state = context.lock().await;
-    let levels: Vec<_> = state.levels.iter().map(|(id, players)| {
-        format!(r#""{}":{{"Players":{}}}"#, id, players.len())
+    let levels: Vec<_> = by_level.iter().map(|(id, (public, rooms))| {
+        let rooms: Vec<_> = rooms.iter().map(|(room, count)| format!(r#""{room}":{count}"#)).collect();
+        format!(r#""{}":{{"Players":{},"Rooms":{{{}}}}}"#, id, public, rooms.join(","))
     }).collect();
-    drop(state);
 
     let mut outer_json = "{\"levels\":{".to_string();
 
@@ -132,7 +386,13 @@ pub fn build_router() -> Router<TSState> {
         .on("/update.version", get(version))
         .on("/isVip.php", get(is_vip))
         .on("/isRainbow.php", get(is_rainbow))
+        .on("/setColor.php", post(set_color))
         .on("/getInfo.php", get(get_info))
         .on("/getIcon.php", get(get_icon))
         .on("/lobbies/:file", get(lobbies))
+        .on("/admin/vip", post(admin_vip))
+        .on("/admin/announce", post(admin_announce))
+        .on("/admin/motd", post(admin_motd))
+        .on("/admin/notice", post(admin_notice))
+        .on("/admin/stats", get(admin_stats))
 }
@@ -1,288 +1,275 @@
-use std::collections::HashMap;
-use std::net::SocketAddr;
-use std::time::{Duration, SystemTime};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::time::Duration;
 
 use anyhow::anyhow;
-use bytebuffer::{ByteBuffer, ByteReader, Endian};
 use log::{debug, info, warn};
+use socket2::{Domain, Socket, Type};
 use std::sync::Arc;
-use tokio::sync::Mutex;
-use crate::state::State;
-
-#[derive(Debug)]
-pub enum Prefixes {
-    Hello = 0x3,
-    Ping = 0x0,
-    Message = 0x1,
-    Disconnect = 0x2,
-    AckHello = 0x4,
-    ServerData = 0x5,
-    PlayerDisconnect = 0x7,
-    PlayerIcons = 0x8,
-    ReceivedPlayerIcons = 0x9,
-    OutsideLevel = 0x10,
-    VipActions = 0x11,
-    BadKey = 0x12,
-}
-
-impl Prefixes {
-    pub fn from_number(value: i8) -> Option<Self> {
-        match value {
-            0 => Some(Prefixes::Ping),
-            1 => Some(Prefixes::Message),
-            2 => Some(Prefixes::Disconnect),
-            3 => Some(Prefixes::Hello),
-            4 => Some(Prefixes::AckHello),
-            5 => Some(Prefixes::ServerData),
-            7 => Some(Prefixes::PlayerDisconnect),
-            8 => Some(Prefixes::PlayerIcons),
-            0x9 => Some(Prefixes::ReceivedPlayerIcons),
-            0x10 => Some(Prefixes::OutsideLevel),
-            0x11 => Some(Prefixes::VipActions),
-            0x12 => Some(Prefixes::BadKey),
-            _ => None,
-        }
-    }
-
-    pub fn to_number(&self) -> i8 {
-        match self {
-            Prefixes::AckHello => 0x4,
-            Prefixes::BadKey => 0x12,
-            Prefixes::Disconnect => 0x2,
-            Prefixes::VipActions => 0x11,
-            Prefixes::OutsideLevel => 0x10,
-            Prefixes::ReceivedPlayerIcons => 0x9,
-            Prefixes::PlayerIcons => 0x8,
-            Prefixes::PlayerDisconnect => 0x7,
-            Prefixes::ServerData => 0x5,
-            Prefixes::Hello => 0x3,
-            Prefixes::Ping => 0x0,
-            Prefixes::Message => 0x1,
-        }
-    }
-}
-
-#[derive(Debug)]
-pub struct PlayerPosition {
-    p1_pos: (i32, i32),
-    p1_rot: (i32, i32),
-    p1_gamemode: u8,
-    p1_icon: u8,
-    p1_size: i32,
-    p1_gravity: u8,
-
-    p2_pos: (i32, i32),
-    p2_rot: (i32, i32),
-    p2_gamemode: u8,
-    p2_icon: u8,
-    p2_size: i32,
-    p2_gravity: u8,
-
-    is_dead: u8,
-    _room: i16,
-
-    color1: u8,
-    color2: u8,
-    glow: u8,
-
-    icon_ids: Vec<u8>,
-}
+use tokio::net::UdpSocket;
+use tokio::task::JoinSet;
+use tokio::time::MissedTickBehavior;
+use crate::buffer_pool::BufferPool;
+use crate::protocol::{
+    self, ClientHeader, LobbyAction, LobbyResult, Prefixes, ProtocolError, ServerDataKind, FEATURE_SESSION_KEYS,
+};
+use crate::config::CookieMode;
+use crate::dispatch::Dispatcher;
+use crate::state::{HelloOutcome, SessionState, State};
+use crate::stats::Stats;
+
+// after this many rejected packets from one address, stop answering them with BadKey
+const BAD_KEY_REPLY_LIMIT: u32 = 20;
+// how often timed out sessions and idle players are looked for
+const REAP_INTERVAL: Duration = Duration::from_secs(5);
+// receive buffers kept around by every worker
+const RECV_POOL_SIZE: usize = 1024;
 
 pub async fn handle_packet(
-    state: Arc<Mutex<State>>,
+    state: Arc<State>,
     buf: &[u8],
     address: SocketAddr,
 ) -> anyhow::Result<()> {
-    let mut bytebuffer = ByteReader::from_bytes(buf);
-    bytebuffer.set_endian(Endian::LittleEndian);
+    let prefix = protocol::peek_prefix(buf)?;
 
-    let prefix = Prefixes::from_number(bytebuffer.read_i8()?).ok_or(anyhow!("invalid prefix"))?;
-
-    let client_id = bytebuffer.read_i32()?;
-    let user_key = bytebuffer.read_u32()?;
+    if prefix != Prefixes::Hello && !authenticate(&state, prefix, buf, address).await? {
+        return Ok(());
+    }
 
     match prefix {
         Prefixes::Disconnect => {
             debug!("remote sent Prefixes::Disconnect");
-            let mut state = state.lock().await;
-            let clients = state.left_level(&client_id);
-            state.notify_clients(&clients, &client_id).await?;
-            state.connected_clients.remove(&client_id);
+            let packet: protocol::Disconnect = protocol::decode(buf)?;
+            let client_id = packet.header.client_id;
+
+            state.close_session(client_id).await;
         }
         Prefixes::Hello => {
             debug!("remote sent Prefixes::Hello");
-            let mut state = state.lock().await;
-            state
-                .connected_clients
-                .insert(client_id, (address, user_key, SystemTime::now()));
+            let packet: protocol::Hello = protocol::decode(buf)?;
+            let client_id = packet.header.client_id;
+
+            // make sure the hello really came from its source address before keeping anything for it,
+            // so spoofed hellos can't fill the session table or bounce replies off us
+            match (state.config.hello_cookies, packet.cookie) {
+                (CookieMode::Off, _) => {}
+                // stock clients can't prove anything, so all that's left is limiting how many sessions
+                // a subnet can open that way
+                (CookieMode::Optional, None) => {
+                    if !state.unverified_hellos.lock().unwrap().allow(address.ip()) {
+                        debug!("dropping hello without a cookie from {address}, too many from its subnet");
+                        return Ok(());
+                    }
+                }
+                (CookieMode::Required, None) => {
+                    debug!("dropping hello without a cookie from {address}");
+                    return Ok(());
+                }
+                (_, Some(cookie)) => {
+                    if !state.cookies.verify(address, client_id, &cookie) {
+                        // the reply is smaller than the hello, so this can't be used for amplification
+                        let cookie = state.cookies.issue(address, client_id);
+                        state
+                            .outbound
+                            .send(address, protocol::encode(&protocol::HelloVerify { cookie }))?;
+                        return Ok(());
+                    }
+                }
+            }
 
-            let mut buf = ByteBuffer::new();
-            buf.write_i8(Prefixes::AckHello.to_number());
-            state.send_to(&client_id, buf.as_bytes()).await?;
-        }
-        Prefixes::Ping => {
-            let res = bytebuffer.read_bytes(20);
-            if res.is_ok() {
-                let mut buf = ByteBuffer::new();
-                buf.set_endian(Endian::LittleEndian);
-                buf.write_i8(Prefixes::Ping.to_number());
-
-                let mut state = state.lock().await;
-                state.update_client_time(&client_id).await;
-
-                buf.write_i32(state.connected_clients.len() as i32); // Online player count
-                state.send_to(&client_id, buf.as_bytes()).await?;
+            // keys rotate on every hello. stock clients ignore AckHello and keep using their own key,
+            // so they get to keep it, and only the address check stands between them and someone guessing it
+            let session_key = if packet.features & FEATURE_SESSION_KEYS != 0 {
+                State::new_session_key()
             } else {
-                warn!("unhandled, got GetIcon or CreateLobby");
+                packet.header.user_key
+            };
+
+            // anyone else connecting with the ID of a timed out session starts over
+            let stale = state
+                .sessions
+                .lock()
+                .unwrap()
+                .is_stale(client_id, address, packet.header.user_key);
+            if stale {
+                state.close_session(client_id).await;
+            }
+
+            let outcome = state.sessions.lock().unwrap().hello(
+                client_id,
+                address,
+                packet.header.user_key,
+                session_key,
+                packet.features,
+            );
+
+            match outcome {
+                HelloOutcome::InUse => {
+                    warn!("refusing hello from {address}, client {client_id} already has a session");
+                    let packet = protocol::BadKey {
+                        reason: "session in use".to_string(),
+                    };
+                    state.outbound.send(address, protocol::encode(&packet))?;
+                    return Ok(());
+                }
+                HelloOutcome::Roam(nonce) => {
+                    // the client says hello again once it answered
+                    debug!("client {client_id} said hello from {address}, sending a roam challenge");
+                    state
+                        .outbound
+                        .send(address, protocol::encode(&protocol::RoamChallenge { nonce }))?;
+                    return Ok(());
+                }
+                HelloOutcome::Accepted { resumed: true } => {
+                    info!("client {client_id} said hello again, resumed their session");
+                }
+                HelloOutcome::Accepted { resumed: false } => {}
+            }
+
+            state.deltas.lock().unwrap().forget(client_id);
+            state.sent_positions.lock().unwrap().forget(client_id);
+
+            // the MOTD waits for the first packet with the key, so a spoofed hello only ever gets this back
+            state
+                .send_to(&client_id, &protocol::encode(&protocol::AckHello { session_key }))
+                .await?;
+        }
+        Prefixes::Ping => match protocol::decode::<protocol::Ping>(buf) {
+            Ok(packet) => {
+                let client_id = packet.header.client_id;
+
+                state.update_client_time(&client_id);
+
+                // the count can be a tick or two behind, nobody notices that in the player list
+                let response = protocol::PingResponse {
+                    online_players: state.snapshot.load().online as i32,
+                };
+                state
+                    .send_to(&client_id, &protocol::encode(&response))
+                    .await?;
+            }
+            Err(ProtocolError::Truncated) => {
+                warn!("unhandled, got GetIcon");
                 // this never happens..?
                 // handle GetIcon
-                // CreateLobby can be passed too, but unimplemented
                 // GetIcon -> 4 bytes - client ID
-                // CreateLobby -> 1 byte - Prefixes::VipActions, 4 bytes - vipkey, u16 - lobby code
+                // CreateLobby comes as its own Prefixes::VipActions packet
             }
-        }
+            Err(e) => return Err(e.into()),
+        },
         Prefixes::OutsideLevel => {
             debug!("remote sent Prefixes::OutsideLevel");
-            let mut state = state.lock().await;
-            let client = state.connected_clients.get(&client_id);
-            let correct_key = client.map(|client| client.1).unwrap_or(0u32);
-
-            if correct_key != user_key {
-                warn!(
-                    "client {client_id} wrong key, expected {correct_key}, client sent {user_key}"
-                );
-                let mut buf = ByteBuffer::new();
-                buf.write_i8(Prefixes::Disconnect.to_number());
-                buf.write_bytes("unauthorized".as_bytes());
-                state.send_to(&client_id, buf.as_bytes()).await?;
-                return Ok(());
-            }
+            let packet: protocol::OutsideLevel = protocol::decode(buf)?;
+            let client_id = packet.header.client_id;
 
             let clients = state.left_level(&client_id);
-            state.notify_clients(&clients, &client_id).await?;
+            state.notify_clients(&clients, &client_id).await;
         }
         Prefixes::Message => {
-            let mut state = state.lock().await;
-            let client = state.connected_clients.get(&client_id);
-            let correct_key = client.map(|client| client.1).unwrap_or(0u32);
-
-            if correct_key != user_key {
-                warn!(
-                    "client {client_id} wrong key, expected {correct_key}, client sent {user_key}"
-                );
-                let mut buf = ByteBuffer::new();
-                buf.write_i8(Prefixes::Disconnect.to_number());
-                buf.write_bytes("unauthorized".as_bytes());
-                state.send_to(&client_id, buf.as_bytes()).await?;
-                return Ok(());
-            }
+            let packet: protocol::Message = protocol::decode(buf)?;
+            let client_id = packet.header.client_id;
+
+            let level_id = packet.level_id;
 
-            let p1_xpos = bytebuffer.read_i32()?;
-            let p1_ypos = bytebuffer.read_i32()?;
-            let p1_xrot = bytebuffer.read_i32()?;
-            let p1_yrot = bytebuffer.read_i32()?;
-            let p1_gamemode = bytebuffer.read_u8()?;
-            let p1_active_icon_id = bytebuffer.read_u8()?;
-            let p1_size = bytebuffer.read_i32()?;
-            let p1_gravity = bytebuffer.read_u8()?;
-
-            let p2_xpos = bytebuffer.read_i32()?;
-            let p2_ypos = bytebuffer.read_i32()?;
-            let p2_xrot = bytebuffer.read_i32()?;
-            let p2_yrot = bytebuffer.read_i32()?;
-            let p2_gamemode = bytebuffer.read_u8()?;
-            let p2_active_icon_id = bytebuffer.read_u8()?;
-            let p2_size = bytebuffer.read_i32()?;
-            let p2_gravity = bytebuffer.read_u8()?;
-
-            let is_dead = bytebuffer.read_u8()?;
-            let level_id = bytebuffer.read_i32()?;
-            let room = bytebuffer.read_i16()?;
-
-            let color1 = bytebuffer.read_u8()?;
-            let color2 = bytebuffer.read_u8()?;
-            let glow = bytebuffer.read_u8()?;
-
-            let icon_ids = bytebuffer.read_bytes(7)?;
+            // overtaken by a newer one on the way
+            if let Some(sequence) = packet.sequence {
+                let accepted = state.deltas.lock().unwrap().accept_incoming(client_id, sequence);
+                if !accepted {
+                    debug!("dropping stale message {sequence} from {client_id}");
+                    return Ok(());
+                }
+            }
 
             if level_id == -1 {
                 let clients = state.left_level(&client_id);
-                state.notify_clients(&clients, &client_id).await?;
+                state.notify_clients(&clients, &client_id).await;
             } else {
-                let level = state.levels.entry(level_id).or_insert_with(HashMap::new);
-
-                let pos_entry = PlayerPosition {
-                    p1_pos: (p1_xpos, p1_ypos),
-                    p1_rot: (p1_xrot, p1_yrot),
-                    p1_gamemode,
-                    p1_icon: p1_active_icon_id,
-                    p1_size,
-                    p1_gravity,
-
-                    p2_pos: (p2_xpos, p2_ypos),
-                    p2_rot: (p2_xrot, p2_yrot),
-                    p2_gamemode,
-                    p2_icon: p2_active_icon_id,
-                    p2_size,
-                    p2_gravity,
-
-                    is_dead,
-                    _room: room,
-
-                    color1,
-                    color2,
-                    glow,
-
-                    icon_ids,
-                };
-
-                if cfg!(debug_assertions) && !level.contains_key(&client_id) {
-                    debug!("{client_id} join the level {level_id}");
+                if !state.can_enter_room(&client_id, packet.room) {
+                    if state.refuse_room(&client_id, packet.room) {
+                        debug!("client {client_id} tried to enter room {} without joining its lobby", packet.room);
+                        let notice = format!("Room {} belongs to a lobby you haven't joined", packet.room);
+                        state
+                            .send_server_data(&client_id, ServerDataKind::LevelNotice, notice)
+                            .await?;
+                    }
+                    return Ok(());
                 }
 
-                level.insert(client_id, pos_entry);
+                let level_key = (level_id, packet.room);
 
-                // get all players on the same level
+                // switched level or room without leaving the previous one first
+                let (clients, joined) = state.enter_level(client_id, level_key, packet.position);
+                state.notify_clients(&clients, &client_id).await;
 
-                let players = state.levels.get(&level_id).unwrap();
+                if cfg!(debug_assertions) && joined {
+                    debug!("{client_id} join the level {level_id} (room {})", packet.room);
+                }
 
-                for (player_id, pos) in players.iter() {
-                    if *player_id == client_id {
-                        continue;
+                if joined {
+                    let notice = state.level_notices.lock().unwrap().get(&level_id).cloned();
+                    if let Some(notice) = notice {
+                        if let Err(e) = state
+                            .send_server_data(&client_id, ServerDataKind::LevelNotice, notice)
+                            .await
+                        {
+                            debug!("failed to send the level notice to {client_id}: {e}");
+                        }
                     }
+                }
 
-                    let mut buf = ByteBuffer::new();
-                    buf.set_endian(Endian::LittleEndian);
-                    buf.write_i8(Prefixes::Message.to_number());
-
-                    buf.write_i32(*player_id);
-                    buf.write_i32(pos.p1_pos.0);
-                    buf.write_i32(pos.p1_pos.1);
-                    buf.write_i32(pos.p1_rot.0);
-                    buf.write_i32(pos.p1_rot.1);
-                    buf.write_u8(pos.p1_gamemode);
-                    buf.write_u8(pos.p1_icon);
-                    buf.write_i32(pos.p1_size);
-                    buf.write_u8(pos.p1_gravity);
-
-                    buf.write_i32(pos.p2_pos.0);
-                    buf.write_i32(pos.p2_pos.1);
-                    buf.write_i32(pos.p2_rot.0);
-                    buf.write_i32(pos.p2_rot.1);
-                    buf.write_u8(pos.p2_gamemode);
-                    buf.write_u8(pos.p2_icon);
-                    buf.write_i32(pos.p2_size);
-                    buf.write_u8(pos.p2_gravity);
-
-                    buf.write_u8(pos.is_dead);
-                    buf.write_u8(0u8); // ActiveIconId - unknown, unused?
-                    buf.write_u8(pos.color1);
-                    buf.write_u8(pos.color2);
-                    buf.write_u8(pos.glow);
-                    buf.write_bytes(&pos.icon_ids);
-
-                    state.send_to(&client_id, buf.as_bytes()).await?;
+                // the other players' positions go out with the next tick
+            }
+        }
+        Prefixes::VipActions => {
+            debug!("remote sent Prefixes::VipActions");
+            let packet: protocol::VipActions = protocol::decode(buf)?;
+            let sender = vip_actions_sender(&state, &packet, address)
+                .ok_or_else(|| anyhow!("VipActions from {address} without a session"))?;
+            let client_id = sender.client_id;
+            let code = packet.lobby_code;
+
+            // the VIP key has to be the session key, so knowing a VIP's ID isn't enough to act as them
+            let result = if packet.vip_key != sender.user_key {
+                warn!("client {client_id} sent VipActions with the wrong VIP key");
+                LobbyResult::NotVip
+            } else {
+                match packet.action {
+                    LobbyAction::Create { name } => state.create_lobby(code, client_id, name).await,
+                    LobbyAction::Join => state.join_lobby(code, client_id).await,
+                    LobbyAction::Leave => {
+                        state.leave_lobby(&client_id).await;
+                        LobbyResult::Ok
+                    }
                 }
+            };
+
+            let response = protocol::VipActionsResult {
+                lobby_code: code,
+                result,
+            };
+            state.send_to(&client_id, &protocol::encode(&response)).await?;
+        }
+        Prefixes::PositionAck => {
+            let packet: protocol::PositionAck = protocol::decode(buf)?;
+
+            state
+                .deltas
+                .lock()
+                .unwrap()
+                .ack(packet.header.client_id, packet.sequence);
+        }
+        Prefixes::Roam => {
+            let packet: protocol::RoamResponse = protocol::decode(buf)?;
+            let client_id = packet.header.client_id;
+
+            let moved = state
+                .sessions
+                .lock()
+                .unwrap()
+                .complete_roam(client_id, address, packet.nonce);
+            if moved {
+                info!("client {client_id} moved to {address}");
+            } else {
+                warn!("client {client_id} sent an invalid roam response from {address}");
             }
         }
         _ => {
@@ -293,35 +280,182 @@ pub async fn handle_packet(
     Ok(())
 }
 
-pub async fn gdm_server(state: Arc<Mutex<State>>, addr: &str) -> anyhow::Result<()> {
-    info!("GDM (UDP) server listening on: {addr}");
+// every packet except Hello has to carry the key the client said hello with
+async fn authenticate(
+    state: &Arc<State>,
+    prefix: Prefixes,
+    buf: &[u8],
+    address: SocketAddr,
+) -> anyhow::Result<bool> {
+    let header = if prefix == Prefixes::VipActions {
+        let packet: protocol::VipActions = protocol::decode(buf)?;
+        let Some(header) = vip_actions_sender(state, &packet, address) else {
+            debug!("VipActions from {address} don't match any session");
+            return Ok(false);
+        };
+        header
+    } else {
+        ClientHeader::peek(buf)?
+    };
+    let client_id = header.client_id;
+
+    // the reply is only sent once the session table is unlocked again
+    let mut greet = false;
+    let reply = {
+        let mut sessions = state.sessions.lock().unwrap();
+        match sessions.check_key(&header) {
+            Ok(()) if sessions.address_of(&client_id) == Some(address) || prefix == Prefixes::Roam => {
+                let left = sessions
+                    .clients
+                    .get_mut(&client_id)
+                    .and_then(|session| session.heard_from());
+                match left {
+                    // the hello really came from the client
+                    Some(SessionState::Connecting) => greet = true,
+                    Some(SessionState::TimedOut { .. }) => {
+                        info!("client {client_id} is back, resumed their session")
+                    }
+                    _ => {}
+                }
+                None
+            }
+            Ok(()) => {
+                // right key from somewhere else, either NAT moved the client or someone is spoofing.
+                // only the real client can receive the challenge at the new address and answer it
+                let nonce = sessions.roam_challenge(client_id, address);
+                debug!("client {client_id} showed up from {address}, sending a roam challenge");
+                Some(protocol::encode(&protocol::RoamChallenge { nonce }))
+            }
+            Err(reason) => {
+                let offenses = sessions.record_bad_key(address.ip());
+                if offenses > BAD_KEY_REPLY_LIMIT {
+                    // don't let a spoofed source address turn us into a reflector
+                    debug!("ignoring bad key from {address} (client {client_id}), {offenses} offenses");
+                    return Ok(false);
+                }
+
+                if offenses == 1 || offenses.is_multiple_of(10) {
+                    warn!("client {client_id} from {address} rejected: {reason} ({offenses} offenses)");
+                }
+
+                Some(protocol::encode(&protocol::BadKey {
+                    reason: reason.to_string(),
+                }))
+            }
+        }
+    };
+
+    if let Some(reply) = reply {
+        state.outbound.send(address, reply)?;
+        return Ok(false);
+    }
 
-    // get the server socket
-    let state_ = state.lock().await;
-    let socket = state_.server_socket.clone();
-    drop(state_);
+    if greet {
+        state.send_motd(&client_id).await;
+    }
+    Ok(true)
+}
 
-    let mut buf = [0u8; 4096];
+// stock clients send VipActions without a header, they're matched to a session by address and VIP key instead
+fn vip_actions_sender(state: &State, packet: &protocol::VipActions, address: SocketAddr) -> Option<ClientHeader> {
+    packet.header.or_else(|| {
+        let client_id = state.sessions.lock().unwrap().client_at(address, packet.vip_key)?;
+        Some(ClientHeader {
+            client_id,
+            user_key: packet.vip_key,
+        })
+    })
+}
+
+/// Binds `workers` sockets to the same address. The kernel spreads incoming packets over them by source address,
+/// so every client always ends up at the same one.
+pub fn bind_sockets(addr: &str, workers: usize) -> anyhow::Result<Vec<Arc<UdpSocket>>> {
+    let addr = addr
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| anyhow!("can't resolve {addr}"))?;
+
+    // elsewhere, SO_REUSEPORT sockets don't share the packets, one of them gets everything
+    let workers = if cfg!(any(target_os = "linux", target_os = "android")) {
+        workers
+    } else {
+        if workers > 1 {
+            warn!("SO_REUSEPORT doesn't spread packets on this platform, receiving on one socket");
+        }
+        1
+    };
+
+    (0..workers)
+        .map(|_| {
+            let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(socket2::Protocol::UDP))?;
+            #[cfg(unix)]
+            socket.set_reuse_port(true)?;
+            socket.set_nonblocking(true)?;
+            socket.bind(&addr.into())?;
+            Ok(Arc::new(UdpSocket::from_std(socket.into())?))
+        })
+        .collect()
+}
+
+pub async fn gdm_server(state: Arc<State>, sockets: Vec<Arc<UdpSocket>>, addr: &str) -> anyhow::Result<()> {
+    info!("GDM (UDP) server listening on: {addr} ({} receive workers)", sockets.len());
+
+    let tick_interval = Duration::from_secs_f64(1.0 / state.config.tick_rate as f64);
 
     let state_cloned = state.clone();
     let _handle = tokio::spawn(async move {
-        let mut interval = tokio::time::interval(Duration::from_secs(30));
+        let mut interval = tokio::time::interval(REAP_INTERVAL);
         interval.tick().await;
         loop {
             interval.tick().await;
-            let mut state = state_cloned.lock().await;
-            debug!("removing dead clients");
-            state.remove_dead_clients().await;
+            state_cloned.remove_dead_clients().await;
+
+            if let Err(e) = state_cloned.vips.lock().await.reload_if_changed().await {
+                warn!("failed to reload the VIP list: {e}");
+            }
+        }
+    });
+
+    // positions are sent at a fixed rate, no matter how often clients send theirs
+    let state_cloned = state.clone();
+    let _tick_handle = tokio::spawn(async move {
+        let mut interval = tokio::time::interval(tick_interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        loop {
+            interval.tick().await;
+            state_cloned.send_positions().await;
+            state_cloned.refresh_snapshot();
         }
     });
 
+    let mut workers = JoinSet::new();
+    for socket in sockets {
+        workers.spawn(receive(state.clone(), socket));
+    }
+
+    while let Some(result) = workers.join_next().await {
+        result??;
+    }
+    Ok(())
+}
+
+// every worker dispatches on its own, a client's packets all arrive at the same one anyway.
+// the rate limiter is shared though, an address can send from as many ports as it likes
+async fn receive(state: Arc<State>, socket: Arc<UdpSocket>) -> anyhow::Result<()> {
+    let stats = state.stats.clone();
+    let mut dispatcher = Dispatcher::new(state.clone());
+    let pool = BufferPool::new(RECV_POOL_SIZE);
+
     loop {
-        let (len, peer) = socket.recv_from(&mut buf).await?;
-        let cloned_state = state.clone();
-        tokio::spawn(async move {
-            if let Err(e) = handle_packet(cloned_state, &buf[..len], peer).await {
-                warn!("remote err from {peer}: {e}");
-            }
-        });
+        let mut buf = pool.take();
+        let (len, peer) = socket.recv_from(buf.as_mut_slice()).await?;
+        buf.set_len(len);
+        Stats::bump(&stats.packets_received);
+
+        if !state.rate_limiter.allow(peer, &buf) {
+            continue;
+        }
+
+        dispatcher.dispatch(buf, peer);
     }
 }
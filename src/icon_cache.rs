@@ -0,0 +1,299 @@
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    path::PathBuf,
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use log::{debug, info, warn};
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use tokio::fs;
+
+use crate::icons::{Form, IconRequest};
+
+struct CacheEntry {
+    size: u64,
+    hash: String, // of the contents, part of the file name and the ETag
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct Index {
+    total_size: u64,
+    entries: HashMap<String, CacheEntry>,
+    clock: u64, // bumped on every access, used for LRU ordering
+}
+
+impl Index {
+    // returns the files that aren't needed anymore, the replaced one and whatever got evicted
+    fn insert(&mut self, key: String, hash: String, size: u64, max_size: u64) -> Vec<String> {
+        let mut obsolete = vec![];
+        if let Some(previous) = self.remove(&key) {
+            if previous.hash != hash {
+                obsolete.push(file_name(&key, &previous.hash));
+            }
+        }
+
+        self.clock += 1;
+        self.total_size += size;
+        self.entries.insert(
+            key,
+            CacheEntry {
+                size,
+                hash,
+                last_used: self.clock,
+            },
+        );
+
+        while self.total_size > max_size {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone());
+
+            let Some(key) = oldest else {
+                break;
+            };
+
+            debug!("evicting cached icon {key}");
+            if let Some(entry) = self.remove(&key) {
+                obsolete.push(file_name(&key, &entry.hash));
+            }
+        }
+
+        obsolete
+    }
+
+    fn touch(&mut self, key: &str) -> Option<&CacheEntry> {
+        self.clock += 1;
+        let entry = self.entries.get_mut(key)?;
+        entry.last_used = self.clock;
+        Some(entry)
+    }
+
+    fn remove(&mut self, key: &str) -> Option<CacheEntry> {
+        let entry = self.entries.remove(key)?;
+        self.total_size -= entry.size;
+        Some(entry)
+    }
+}
+
+/// On-disk cache for `getIcon.php` responses. Files are named `<key>-<hash>.png`, where the key is the hash
+/// of the normalized query and the hash is that of the contents, so the index is rebuilt from the file names alone.
+/// The least recently used ones are evicted once the cache grows past `max_size` bytes.
+/// The index is only locked to look things up, never while a file is read or written.
+pub struct IconCache {
+    dir: PathBuf,
+    max_size: u64,
+    index: Mutex<Index>,
+}
+
+impl IconCache {
+    pub async fn open(dir: PathBuf, max_size: u64) -> anyhow::Result<Self> {
+        fs::create_dir_all(&dir).await?;
+
+        let mut files = vec![];
+        let mut stale = vec![];
+        let mut read_dir = fs::read_dir(&dir).await?;
+        while let Some(entry) = read_dir.next_entry().await? {
+            let meta = entry.metadata().await?;
+            if !meta.is_file() {
+                continue;
+            }
+
+            let name = entry.file_name().to_string_lossy().to_string();
+            match parse_file_name(&name) {
+                Some((key, hash)) => {
+                    let modified = meta.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                    files.push((key.to_string(), hash.to_string(), meta.len(), modified));
+                }
+                // half written, or from before the hash was in the name
+                None => stale.push(name),
+            }
+        }
+
+        // oldest files count as the least recently used ones
+        files.sort_by_key(|(_, _, _, modified)| *modified);
+
+        let mut index = Index::default();
+        for (key, hash, size, _) in files {
+            stale.extend(index.insert(key, hash, size, max_size));
+        }
+
+        info!("icon cache has {} entries, {} bytes", index.entries.len(), index.total_size);
+
+        let cache = IconCache {
+            dir,
+            max_size,
+            index: Mutex::new(index),
+        };
+        cache.remove_files(stale).await;
+        Ok(cache)
+    }
+
+    /// Cache key of an icon. It's made from the parsed request, so `col1=01` and `col1=1`, or `glow=1`
+    /// and `glow=true`, are the same icon. The cube only matters for the forms it rides in.
+    pub fn key(request: &IconRequest) -> String {
+        let cube = match request.form {
+            Form::Ship | Form::Ufo => request.cube,
+            _ => 0,
+        };
+        let canonical = format!(
+            "{:?}:{}:{}:{}:{}:{}",
+            request.form, request.icon, cube, request.col1, request.col2, request.glow
+        );
+
+        to_hex(&Sha256::digest(canonical.as_bytes()))
+    }
+
+    /// ETag of the cached icon, if its file is still there.
+    pub async fn etag(&self, key: &str) -> Option<String> {
+        let hash = self.index.lock().unwrap().entries.get(key)?.hash.clone();
+
+        match fs::metadata(self.dir.join(file_name(key, &hash))).await {
+            Ok(_) => Some(etag(&hash)),
+            Err(e) => {
+                self.lost(key, &hash, e);
+                None
+            }
+        }
+    }
+
+    /// The cached icon and its ETag.
+    pub async fn get(&self, key: &str) -> Option<(Vec<u8>, String)> {
+        let hash = self.index.lock().unwrap().touch(key)?.hash.clone();
+
+        match fs::read(self.dir.join(file_name(key, &hash))).await {
+            Ok(data) => Some((data, etag(&hash))),
+            Err(e) => {
+                self.lost(key, &hash, e);
+                None
+            }
+        }
+    }
+
+    /// Stores the icon and returns its ETag.
+    pub async fn put(&self, key: &str, data: &[u8]) -> anyhow::Result<String> {
+        let hash = to_hex(&Sha256::digest(data))[..32].to_string();
+        let name = file_name(key, &hash);
+
+        // written under a temporary name first, so nobody ever reads half an icon
+        let temp = self.dir.join(format!("{name}.{:08x}.tmp", rand::thread_rng().gen::<u32>()));
+        fs::write(&temp, data).await?;
+        if let Err(e) = fs::rename(&temp, self.dir.join(&name)).await {
+            let _ = fs::remove_file(&temp).await;
+            return Err(e.into());
+        }
+
+        let obsolete = self
+            .index
+            .lock()
+            .unwrap()
+            .insert(key.to_string(), hash.clone(), data.len() as u64, self.max_size);
+        self.remove_files(obsolete).await;
+
+        Ok(etag(&hash))
+    }
+
+    // the file went away behind our back, so the icon is made again next time
+    fn lost(&self, key: &str, hash: &str, e: std::io::Error) {
+        if e.kind() != ErrorKind::NotFound {
+            warn!("failed to read cached icon {key}: {e}");
+        }
+
+        let mut index = self.index.lock().unwrap();
+        // unless it was replaced in the meantime
+        if index.entries.get(key).is_some_and(|entry| entry.hash == hash) {
+            index.remove(key);
+        }
+    }
+
+    async fn remove_files(&self, names: Vec<String>) {
+        for name in names {
+            if let Err(e) = fs::remove_file(self.dir.join(&name)).await {
+                if e.kind() != ErrorKind::NotFound {
+                    warn!("failed to remove cached icon {name}: {e}");
+                }
+            }
+        }
+    }
+}
+
+fn file_name(key: &str, hash: &str) -> String {
+    format!("{key}-{hash}.png")
+}
+
+fn parse_file_name(name: &str) -> Option<(&str, &str)> {
+    let (key, hash) = name.strip_suffix(".png")?.split_once('-')?;
+    let is_hex = |value: &str| !value.is_empty() && value.chars().all(|c| c.is_ascii_hexdigit());
+    (is_hex(key) && is_hex(hash)).then_some((key, hash))
+}
+
+fn etag(hash: &str) -> String {
+    format!("\"{hash}\"")
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn least_recently_used_icons_are_evicted() {
+        let mut index = Index::default();
+        assert!(index.insert("aa".into(), "01".into(), 40, 100).is_empty());
+        assert!(index.insert("bb".into(), "02".into(), 40, 100).is_empty());
+        index.touch("aa");
+
+        assert_eq!(index.insert("cc".into(), "03".into(), 40, 100), vec!["bb-02.png"]);
+        assert_eq!(index.total_size, 80);
+    }
+
+    #[test]
+    fn replaced_icons_drop_their_old_file() {
+        let mut index = Index::default();
+        index.insert("aa".into(), "01".into(), 40, 100);
+
+        assert!(index.insert("aa".into(), "01".into(), 40, 100).is_empty());
+        assert_eq!(index.insert("aa".into(), "02".into(), 50, 100), vec!["aa-01.png"]);
+        assert_eq!(index.total_size, 50);
+    }
+
+    fn request(form: Form, cube: u32) -> IconRequest {
+        IconRequest {
+            form,
+            icon: 12,
+            cube,
+            col1: 1,
+            col2: 3,
+            glow: true,
+        }
+    }
+
+    #[test]
+    fn keys_only_depend_on_what_changes_the_icon() {
+        assert_eq!(IconCache::key(&request(Form::Cube, 1)), IconCache::key(&request(Form::Cube, 40)));
+        assert_ne!(IconCache::key(&request(Form::Ship, 1)), IconCache::key(&request(Form::Ship, 40)));
+        assert_ne!(IconCache::key(&request(Form::Cube, 1)), IconCache::key(&request(Form::Ball, 1)));
+
+        let without_glow = IconRequest {
+            glow: false,
+            ..request(Form::Cube, 1)
+        };
+        assert_ne!(IconCache::key(&request(Form::Cube, 1)), IconCache::key(&without_glow));
+    }
+
+    #[test]
+    fn only_cache_files_are_recognized() {
+        assert_eq!(parse_file_name("ab12-cd34.png"), Some(("ab12", "cd34")));
+        assert_eq!(parse_file_name("ab12-cd34.png.0badf00d.tmp"), None);
+        assert_eq!(parse_file_name("ab12"), None);
+        assert_eq!(parse_file_name("notes-here.png"), None);
+    }
+}
@@ -0,0 +1,752 @@
+use std::{
+    collections::HashMap,
+    io::{Cursor, ErrorKind},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::anyhow;
+use image::{imageops, DynamicImage, ImageOutputFormat, Rgba, RgbaImage};
+use log::{info, warn};
+
+// the GD color palette, indexed by the col1 and col2 params
+const PALETTE: [[u8; 3]; 42] = [
+    [125, 255, 0],
+    [0, 255, 0],
+    [0, 255, 125],
+    [0, 255, 255],
+    [0, 125, 255],
+    [0, 0, 255],
+    [125, 0, 255],
+    [255, 0, 255],
+    [255, 0, 125],
+    [255, 0, 0],
+    [255, 125, 0],
+    [255, 255, 0],
+    [255, 255, 255],
+    [185, 0, 255],
+    [255, 185, 0],
+    [0, 0, 0],
+    [0, 200, 255],
+    [175, 175, 175],
+    [90, 90, 90],
+    [255, 125, 125],
+    [0, 175, 75],
+    [0, 125, 125],
+    [0, 75, 175],
+    [75, 0, 175],
+    [125, 0, 125],
+    [175, 0, 75],
+    [175, 75, 0],
+    [125, 125, 0],
+    [75, 175, 0],
+    [255, 75, 0],
+    [150, 50, 0],
+    [150, 100, 0],
+    [100, 150, 0],
+    [0, 150, 100],
+    [0, 100, 150],
+    [100, 0, 150],
+    [150, 0, 100],
+    [150, 0, 0],
+    [0, 150, 0],
+    [0, 0, 150],
+    [125, 255, 175],
+    [125, 125, 255],
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Form {
+    Cube,
+    Ship,
+    Ball,
+    Ufo,
+    Wave,
+    Robot,
+    Spider,
+}
+
+impl Form {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "cube" | "icon" | "player" => Some(Form::Cube),
+            "ship" => Some(Form::Ship),
+            "ball" => Some(Form::Ball),
+            "ufo" | "bird" => Some(Form::Ufo),
+            "wave" | "dart" => Some(Form::Wave),
+            "robot" => Some(Form::Robot),
+            "spider" => Some(Form::Spider),
+            _ => None,
+        }
+    }
+
+    // frame names in the game's sprite sheets
+    fn sprite_prefix(&self) -> &'static str {
+        match self {
+            Form::Cube => "player",
+            Form::Ship => "ship",
+            Form::Ball => "player_ball",
+            Form::Ufo => "bird",
+            Form::Wave => "dart",
+            Form::Robot => "robot",
+            Form::Spider => "spider",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct IconRequest {
+    pub form: Form,
+    pub icon: u32,
+    pub cube: u32, // rides in ships and UFOs
+    pub col1: u8,
+    pub col2: u8,
+    pub glow: bool,
+}
+
+// one part of a robot or spider in its idle pose. positions are in points from the center, y up,
+// rotation is clockwise in degrees, and back parts are drawn darker behind the body
+struct Joint {
+    part: u8,
+    x: f32,
+    y: f32,
+    rotation: f32,
+    back: bool,
+}
+
+// the idle frames of the game's robot and spider animations, back to front
+const ROBOT_POSE: [Joint; 7] = [
+    Joint { part: 2, x: -3.5, y: -7.0, rotation: -35.0, back: true },
+    Joint { part: 3, x: -7.0, y: -13.5, rotation: 10.0, back: true },
+    Joint { part: 4, x: -5.5, y: -19.0, rotation: 0.0, back: true },
+    Joint { part: 1, x: 0.0, y: 4.5, rotation: 0.0, back: false },
+    Joint { part: 2, x: 2.5, y: -7.5, rotation: -15.0, back: false },
+    Joint { part: 3, x: 0.5, y: -14.0, rotation: 25.0, back: false },
+    Joint { part: 4, x: 3.5, y: -19.0, rotation: 0.0, back: false },
+];
+
+const SPIDER_POSE: [Joint; 5] = [
+    Joint { part: 2, x: 5.5, y: -10.5, rotation: 0.0, back: true },
+    Joint { part: 3, x: -12.5, y: -10.0, rotation: 0.0, back: true },
+    Joint { part: 1, x: 0.0, y: 3.5, rotation: 0.0, back: false },
+    Joint { part: 2, x: -5.0, y: -11.0, rotation: 0.0, back: false },
+    Joint { part: 4, x: 13.0, y: -10.5, rotation: 0.0, back: false },
+];
+
+// the cube in a ship or UFO, relative to the vehicle
+const RIDER_SCALE: f32 = 0.55;
+const SHIP_RIDER_Y: f32 = 10.0;
+const UFO_RIDER_Y: f32 = 5.5;
+const BACK_PART_SHADE: f32 = 0.6;
+
+/// Where a frame is in its texture, as listed in the sheet's plist.
+#[derive(Debug, Clone, PartialEq)]
+struct Frame {
+    texture: usize,
+    rect: (u32, u32, u32, u32), // x, y, width, height, before rotating
+    rotated: bool,              // stored turned 90° clockwise
+    offset: (f32, f32),         // of the trimmed sprite from the center of the untrimmed one, y up
+    scale: f32,                 // pixels per point, 4 for -uhd sheets and 2 for -hd ones
+}
+
+/// A sprite cut out of a sheet, with its offset from the center of whatever it's part of in pixels, y up.
+struct Sprite {
+    image: RgbaImage,
+    offset: (f32, f32),
+}
+
+// the layers of one part of an icon. whatever rides in a vehicle goes between the two
+struct Part {
+    behind: Vec<Sprite>, // glow, then the UFO dome
+    front: Vec<Sprite>,  // secondary, primary and the untinted extra
+    scale: f32,
+}
+
+/// Frames of the game's sprite sheets (`GJ_GameSheet02-uhd.plist` or the per icon sheets like `player_01-uhd.plist`,
+/// each with its png next to it). Only the plists are read up front, textures are loaded the first time they're used.
+pub struct IconSheets {
+    frames: HashMap<String, Frame>,
+    textures: Vec<PathBuf>,
+    loaded: Mutex<HashMap<usize, Arc<RgbaImage>>>,
+}
+
+impl IconSheets {
+    pub fn load(dir: &Path) -> anyhow::Result<Self> {
+        let mut sheets = IconSheets {
+            frames: HashMap::new(),
+            textures: vec![],
+            loaded: Mutex::new(HashMap::new()),
+        };
+
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                info!("no sprite sheets in {dir:?}, icons won't be rendered");
+                return Ok(sheets);
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_some_and(|extension| extension == "plist") {
+                if let Err(e) = sheets.add_sheet(&path) {
+                    warn!("ignoring the sprite sheet {path:?}: {e}");
+                }
+            }
+        }
+
+        info!("loaded {} sprites from {} sheets in {dir:?}", sheets.frames.len(), sheets.textures.len());
+        Ok(sheets)
+    }
+
+    fn add_sheet(&mut self, path: &Path) -> anyhow::Result<()> {
+        let plist = parse_plist(&std::fs::read_to_string(path)?)?;
+
+        let texture = plist
+            .get("metadata")
+            .and_then(|metadata| metadata.get("realTextureFileName").or(metadata.get("textureFileName")))
+            .and_then(Plist::as_str)
+            .map(|name| path.with_file_name(name))
+            .unwrap_or_else(|| path.with_extension("png"));
+
+        let stem = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or_default();
+        let scale = if stem.ends_with("-uhd") {
+            4.0
+        } else if stem.ends_with("-hd") {
+            2.0
+        } else {
+            1.0
+        };
+
+        let Some(Plist::Dict(frames)) = plist.get("frames") else {
+            return Err(anyhow!("no frames"));
+        };
+
+        let index = self.textures.len();
+        self.textures.push(texture);
+
+        for (name, frame) in frames.iter() {
+            let Some(frame) = parse_frame(frame, index, scale) else {
+                warn!("ignoring the invalid frame {name:?} in {path:?}");
+                continue;
+            };
+
+            // the same frame comes in several resolutions, the sharpest one wins
+            if self.frames.get(name).is_none_or(|existing| existing.scale < frame.scale) {
+                self.frames.insert(name.clone(), frame);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Renders an icon and encodes it as PNG. Ships and UFOs carry the cube from `cube`,
+    /// robots and spiders are put together from their parts. Returns `None` if a sprite is missing.
+    pub fn render(&self, request: &IconRequest) -> anyhow::Result<Option<Vec<u8>>> {
+        let col1 = color(request.col1);
+        let col2 = color(request.col2);
+        let base = format!("{}_{:02}", request.form.sprite_prefix(), request.icon);
+
+        let mut layers = vec![];
+        match request.form {
+            Form::Robot | Form::Spider => {
+                let pose: &[Joint] = if request.form == Form::Robot {
+                    &ROBOT_POSE
+                } else {
+                    &SPIDER_POSE
+                };
+
+                for joint in pose.iter() {
+                    let Some(part) = self.part(&format!("{base}_{:02}", joint.part), col1, col2, request.glow)? else {
+                        return Ok(None);
+                    };
+
+                    let position = (joint.x * part.scale, joint.y * part.scale);
+                    for sprite in part.behind.into_iter().chain(part.front) {
+                        let mut sprite = rotate(sprite, joint.rotation);
+                        if joint.back {
+                            shade(&mut sprite.image, BACK_PART_SHADE);
+                        }
+                        sprite.offset.0 += position.0;
+                        sprite.offset.1 += position.1;
+                        layers.push(sprite);
+                    }
+                }
+            }
+            Form::Ship | Form::Ufo => {
+                let Some(vehicle) = self.part(&base, col1, col2, request.glow)? else {
+                    return Ok(None);
+                };
+
+                // sits in the cockpit, or under the dome
+                let rider_y = if request.form == Form::Ship {
+                    SHIP_RIDER_Y
+                } else {
+                    UFO_RIDER_Y
+                };
+                let rider = self.part(&format!("player_{:02}", request.cube), col1, col2, false)?;
+
+                layers.extend(vehicle.behind);
+                for sprite in rider.into_iter().flat_map(|rider| rider.front) {
+                    let mut sprite = resize(sprite, RIDER_SCALE);
+                    sprite.offset.1 += rider_y * vehicle.scale;
+                    layers.push(sprite);
+                }
+                layers.extend(vehicle.front);
+            }
+            Form::Cube | Form::Ball | Form::Wave => {
+                let Some(part) = self.part(&base, col1, col2, request.glow)? else {
+                    return Ok(None);
+                };
+                layers.extend(part.behind);
+                layers.extend(part.front);
+            }
+        }
+
+        Ok(Some(encode(compose(&layers))?))
+    }
+
+    // every layer of the part named `base`, like `player_01` or `robot_01_02`. None if it has no primary sprite
+    fn part(&self, base: &str, col1: [u8; 3], col2: [u8; 3], glow: bool) -> anyhow::Result<Option<Part>> {
+        let Some(frame) = self.frames.get(&format!("{base}_001.png")) else {
+            return Ok(None);
+        };
+        let scale = frame.scale;
+        let primary = self.sprite(frame)?;
+
+        let mut behind = vec![];
+        if glow {
+            if let Some(glow) = self.named_sprite(&format!("{base}_glow_001.png"))? {
+                behind.push(tinted(glow, col2));
+            }
+        }
+        if let Some(dome) = self.named_sprite(&format!("{base}_3_001.png"))? {
+            behind.push(dome);
+        }
+
+        let mut front = vec![];
+        if let Some(secondary) = self.named_sprite(&format!("{base}_2_001.png"))? {
+            front.push(tinted(secondary, col2));
+        }
+        front.push(tinted(primary, col1));
+        if let Some(extra) = self.named_sprite(&format!("{base}_extra_001.png"))? {
+            front.push(extra);
+        }
+
+        Ok(Some(Part { behind, front, scale }))
+    }
+
+    fn named_sprite(&self, name: &str) -> anyhow::Result<Option<Sprite>> {
+        self.frames.get(name).map(|frame| self.sprite(frame)).transpose()
+    }
+
+    fn sprite(&self, frame: &Frame) -> anyhow::Result<Sprite> {
+        let texture = self.texture(frame.texture)?;
+
+        let (x, y, width, height) = frame.rect;
+        let (stored_width, stored_height) = if frame.rotated { (height, width) } else { (width, height) };
+        if x + stored_width > texture.width() || y + stored_height > texture.height() {
+            return Err(anyhow!("frame is outside of {:?}", self.textures[frame.texture]));
+        }
+
+        let mut image = imageops::crop_imm(texture.as_ref(), x, y, stored_width, stored_height).to_image();
+        if frame.rotated {
+            image = imageops::rotate270(&image);
+        }
+
+        Ok(Sprite {
+            image,
+            offset: frame.offset,
+        })
+    }
+
+    fn texture(&self, index: usize) -> anyhow::Result<Arc<RgbaImage>> {
+        if let Some(texture) = self.loaded.lock().unwrap().get(&index) {
+            return Ok(texture.clone());
+        }
+
+        // loaded without the lock, two requests racing for the same sheet just both load it
+        let texture = Arc::new(image::open(&self.textures[index])?.to_rgba8());
+        self.loaded.lock().unwrap().insert(index, texture.clone());
+        Ok(texture)
+    }
+}
+
+fn parse_frame(frame: &Plist, texture: usize, scale: f32) -> Option<Frame> {
+    // format 3 sheets, which is what the game ships, and the older format 2 key names
+    let rect = frame.get("textureRect").or(frame.get("frame"))?.as_str()?;
+    let rotated = frame.get("textureRotated").or(frame.get("rotated"));
+    let offset = frame.get("spriteOffset").or(frame.get("offset"))?.as_str()?;
+
+    let rect = parse_numbers(rect)?;
+    let offset = parse_numbers(offset)?;
+    if rect.len() != 4 || offset.len() != 2 || rect.iter().any(|value| *value < 0.0) {
+        return None;
+    }
+
+    Some(Frame {
+        texture,
+        rect: (rect[0] as u32, rect[1] as u32, rect[2] as u32, rect[3] as u32),
+        rotated: matches!(rotated, Some(Plist::Bool(true))),
+        offset: (offset[0], offset[1]),
+        scale,
+    })
+}
+
+// "{{2,4},{60,58}}" or "{0,-0.5}"
+fn parse_numbers(value: &str) -> Option<Vec<f32>> {
+    value
+        .split(['{', '}', ','])
+        .map(str::trim)
+        .filter(|number| !number.is_empty())
+        .map(|number| number.parse().ok())
+        .collect()
+}
+
+// lays the sprites out around a common center and crops the canvas to fit all of them
+fn compose(layers: &[Sprite]) -> RgbaImage {
+    let bounds = layers.iter().fold(None, |bounds: Option<(f32, f32, f32, f32)>, sprite| {
+        let half_width = sprite.image.width() as f32 / 2.0;
+        let half_height = sprite.image.height() as f32 / 2.0;
+        let (x, y) = sprite.offset;
+        let sprite_bounds = (x - half_width, y + half_height, x + half_width, y - half_height);
+
+        Some(match bounds {
+            None => sprite_bounds,
+            Some((left, top, right, bottom)) => (
+                left.min(sprite_bounds.0),
+                top.max(sprite_bounds.1),
+                right.max(sprite_bounds.2),
+                bottom.min(sprite_bounds.3),
+            ),
+        })
+    });
+
+    let Some((left, top, right, bottom)) = bounds else {
+        return RgbaImage::new(1, 1);
+    };
+
+    let mut canvas = RgbaImage::new((right - left).ceil() as u32, (top - bottom).ceil() as u32);
+    for sprite in layers.iter() {
+        let x = sprite.offset.0 - sprite.image.width() as f32 / 2.0 - left;
+        let y = top - (sprite.offset.1 + sprite.image.height() as f32 / 2.0);
+        imageops::overlay(&mut canvas, &sprite.image, x.round() as i64, y.round() as i64);
+    }
+
+    canvas
+}
+
+fn resize(sprite: Sprite, scale: f32) -> Sprite {
+    let width = ((sprite.image.width() as f32 * scale).round() as u32).max(1);
+    let height = ((sprite.image.height() as f32 * scale).round() as u32).max(1);
+
+    Sprite {
+        image: imageops::resize(&sprite.image, width, height, imageops::FilterType::Triangle),
+        offset: (sprite.offset.0 * scale, sprite.offset.1 * scale),
+    }
+}
+
+// turns the sprite clockwise around the center of its part, growing the image to fit the corners
+fn rotate(sprite: Sprite, degrees: f32) -> Sprite {
+    if degrees == 0.0 {
+        return sprite;
+    }
+
+    let (sin, cos) = degrees.to_radians().sin_cos();
+    let (width, height) = (sprite.image.width() as f32, sprite.image.height() as f32);
+    let new_width = (width * cos.abs() + height * sin.abs()).ceil();
+    let new_height = (width * sin.abs() + height * cos.abs()).ceil();
+
+    // every pixel of the result is looked up in the original, turned the other way
+    let mut image = RgbaImage::new(new_width as u32, new_height as u32);
+    for (x, y, pixel) in image.enumerate_pixels_mut() {
+        let dx = x as f32 + 0.5 - new_width / 2.0;
+        let dy = y as f32 + 0.5 - new_height / 2.0;
+        let source_x = dx * cos + dy * sin + width / 2.0;
+        let source_y = -dx * sin + dy * cos + height / 2.0;
+
+        if source_x >= 0.0 && source_y >= 0.0 && source_x < width && source_y < height {
+            *pixel = *sprite.image.get_pixel(source_x as u32, source_y as u32);
+        }
+    }
+
+    // y points up for offsets, so clockwise is the negative direction
+    let (x, y) = sprite.offset;
+    Sprite {
+        image,
+        offset: (x * cos + y * sin, -x * sin + y * cos),
+    }
+}
+
+fn tinted(mut sprite: Sprite, color: [u8; 3]) -> Sprite {
+    tint(&mut sprite.image, color);
+    sprite
+}
+
+fn shade(image: &mut RgbaImage, factor: f32) {
+    for pixel in image.pixels_mut() {
+        for channel in 0..3 {
+            pixel[channel] = (pixel[channel] as f32 * factor) as u8;
+        }
+    }
+}
+
+/// Just enough of Apple's XML property lists to read Cocos2d sprite sheets.
+#[derive(Debug, Clone, PartialEq)]
+enum Plist {
+    Dict(Vec<(String, Plist)>),
+    String(String),
+    Bool(bool),
+    Other, // arrays and numbers, sprite sheets don't need them
+}
+
+impl Plist {
+    fn get(&self, key: &str) -> Option<&Plist> {
+        match self {
+            Plist::Dict(entries) => entries.iter().find(|(name, _)| name == key).map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Plist::String(value) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+fn parse_plist(xml: &str) -> anyhow::Result<Plist> {
+    let mut reader = PlistReader { rest: xml };
+    loop {
+        let tag = reader.next_tag().ok_or(anyhow!("no plist value"))?;
+        // the xml declaration, the doctype and the <plist> element itself
+        if tag.starts_with('?') || tag.starts_with('!') || tag.starts_with("plist") {
+            continue;
+        }
+        return reader.value(tag);
+    }
+}
+
+struct PlistReader<'a> {
+    rest: &'a str,
+}
+
+impl<'a> PlistReader<'a> {
+    // the inside of the next tag, skipping whitespace and comments
+    fn next_tag(&mut self) -> Option<&'a str> {
+        loop {
+            let start = self.rest.find('<')?;
+            if self.rest[start..].starts_with("<!--") {
+                let end = self.rest[start..].find("-->")?;
+                self.rest = &self.rest[start + end + 3..];
+                continue;
+            }
+
+            let end = self.rest[start..].find('>')?;
+            let tag = &self.rest[start + 1..start + end];
+            self.rest = &self.rest[start + end + 1..];
+            return Some(tag.trim());
+        }
+    }
+
+    fn text(&mut self, closing: &str) -> anyhow::Result<String> {
+        let end = self.rest.find(closing).ok_or(anyhow!("missing {closing}"))?;
+        let text = &self.rest[..end];
+        self.rest = &self.rest[end + closing.len()..];
+
+        Ok(text
+            .replace("&lt;", "<")
+            .replace("&gt;", ">")
+            .replace("&quot;", "\"")
+            .replace("&apos;", "'")
+            .replace("&amp;", "&"))
+    }
+
+    fn value(&mut self, tag: &str) -> anyhow::Result<Plist> {
+        match tag {
+            "dict" => {
+                let mut entries = vec![];
+                loop {
+                    match self.next_tag().ok_or(anyhow!("unclosed dict"))? {
+                        "/dict" => return Ok(Plist::Dict(entries)),
+                        "key" => {
+                            let key = self.text("</key>")?;
+                            let tag = self.next_tag().ok_or(anyhow!("no value for {key:?}"))?;
+                            entries.push((key, self.value(tag)?));
+                        }
+                        other => return Err(anyhow!("unexpected <{other}> in a dict")),
+                    }
+                }
+            }
+            "array" => loop {
+                match self.next_tag().ok_or(anyhow!("unclosed array"))? {
+                    "/array" => return Ok(Plist::Other),
+                    tag => {
+                        self.value(tag)?;
+                    }
+                }
+            },
+            "dict/" => Ok(Plist::Dict(vec![])),
+            "array/" => Ok(Plist::Other),
+            "string" => Ok(Plist::String(self.text("</string>")?)),
+            "string/" => Ok(Plist::String(String::new())),
+            "integer" => self.text("</integer>").map(|_| Plist::Other),
+            "real" => self.text("</real>").map(|_| Plist::Other),
+            "true/" => Ok(Plist::Bool(true)),
+            "false/" => Ok(Plist::Bool(false)),
+            other => Err(anyhow!("unsupported plist element <{other}>")),
+        }
+    }
+}
+
+/// Plain translucent square, served when an icon can't be rendered or fetched from anywhere.
+pub fn placeholder() -> anyhow::Result<Vec<u8>> {
+    encode(RgbaImage::from_pixel(64, 64, Rgba([128, 128, 128, 160])))
+}
+
+fn encode(image: RgbaImage) -> anyhow::Result<Vec<u8>> {
+    let mut png = Cursor::new(Vec::new());
+    DynamicImage::ImageRgba8(image).write_to(&mut png, ImageOutputFormat::Png)?;
+    Ok(png.into_inner())
+}
+
+fn color(index: u8) -> [u8; 3] {
+    PALETTE.get(index as usize).copied().unwrap_or([255, 255, 255])
+}
+
+// tinted layers are grayscale in the sprite sheets, so multiplying gives the final color
+fn tint(layer: &mut RgbaImage, color: [u8; 3]) {
+    for pixel in layer.pixels_mut() {
+        for (channel, value) in color.iter().enumerate() {
+            pixel[channel] = (pixel[channel] as u16 * *value as u16 / 255) as u8;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHEET: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple Computer//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+    <dict>
+        <key>frames</key>
+        <dict>
+            <key>player_01_001.png</key>
+            <dict>
+                <key>aliases</key>
+                <array/>
+                <key>spriteOffset</key>
+                <string>{1,-1}</string>
+                <key>spriteSize</key>
+                <string>{4,2}</string>
+                <key>spriteSourceSize</key>
+                <string>{6,4}</string>
+                <key>textureRect</key>
+                <string>{{0,0},{4,2}}</string>
+                <key>textureRotated</key>
+                <false/>
+            </dict>
+            <!-- stored on its side -->
+            <key>player_01_2_001.png</key>
+            <dict>
+                <key>spriteOffset</key>
+                <string>{0,0}</string>
+                <key>textureRect</key>
+                <string>{{4,0},{3,2}}</string>
+                <key>textureRotated</key>
+                <true/>
+            </dict>
+        </dict>
+        <key>metadata</key>
+        <dict>
+            <key>format</key>
+            <integer>3</integer>
+            <key>textureFileName</key>
+            <string>sheet-uhd.png</string>
+        </dict>
+    </dict>
+</plist>"#;
+
+    const WHITE: Rgba<u8> = Rgba([255, 255, 255, 255]);
+
+    // a sheet with a white 4x2 primary, and a 3x2 white secondary that's stored turned, as 2x3
+    fn sheets(test: &str) -> IconSheets {
+        let dir = std::env::temp_dir().join(format!("opengdm-icons-{test}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut texture = RgbaImage::new(8, 4);
+        for (x, y, pixel) in texture.enumerate_pixels_mut() {
+            *pixel = match (x, y) {
+                (0..=3, 0..=1) => WHITE,
+                // the top left corner of the secondary ends up top right once it's stored turned
+                (5, 0) => Rgba([0, 0, 255, 255]),
+                (4..=5, 0..=2) => WHITE,
+                _ => Rgba([0, 0, 0, 0]),
+            };
+        }
+        texture.save(dir.join("sheet-uhd.png")).unwrap();
+        std::fs::write(dir.join("sheet-uhd.plist"), SHEET).unwrap();
+
+        IconSheets::load(&dir).unwrap()
+    }
+
+    #[test]
+    fn frames_are_read_from_the_plist() {
+        let sheets = sheets("frames_are_read_from_the_plist");
+
+        assert_eq!(
+            sheets.frames["player_01_001.png"],
+            Frame {
+                texture: 0,
+                rect: (0, 0, 4, 2),
+                rotated: false,
+                offset: (1.0, -1.0),
+                scale: 4.0,
+            }
+        );
+        assert!(sheets.frames["player_01_2_001.png"].rotated);
+        assert_eq!(sheets.frames.len(), 2);
+    }
+
+    #[test]
+    fn rotated_frames_are_turned_back() {
+        let sheets = sheets("rotated_frames_are_turned_back");
+        let sprite = sheets.named_sprite("player_01_2_001.png").unwrap().unwrap();
+
+        assert_eq!(sprite.image.dimensions(), (3, 2));
+        assert_eq!(*sprite.image.get_pixel(0, 0), Rgba([0, 0, 255, 255]));
+        assert_eq!(*sprite.image.get_pixel(2, 1), WHITE);
+    }
+
+    #[test]
+    fn layers_are_tinted_and_composed() {
+        let sheets = sheets("layers_are_tinted_and_composed");
+        let part = sheets.part("player_01", [0, 255, 0], [255, 255, 255], false).unwrap().unwrap();
+        let canvas = compose(&part.behind.into_iter().chain(part.front).collect::<Vec<_>>());
+
+        // the primary is 4 wide and 1 right of the center, the secondary 3 wide and centered
+        assert_eq!(canvas.dimensions(), (5, 3));
+        assert_eq!(*canvas.get_pixel(4, 2), Rgba([0, 255, 0, 255]));
+    }
+
+    #[test]
+    fn missing_parts_are_not_rendered() {
+        let sheets = sheets("missing_parts_are_not_rendered");
+        let request = IconRequest {
+            form: Form::Robot,
+            icon: 1,
+            cube: 1,
+            col1: 0,
+            col2: 3,
+            glow: true,
+        };
+
+        assert!(sheets.render(&request).unwrap().is_none());
+        assert!(sheets.render(&IconRequest { form: Form::Ship, ..request.clone() }).unwrap().is_none());
+        assert!(sheets.render(&IconRequest { form: Form::Cube, ..request }).unwrap().is_some());
+    }
+}
@@ -0,0 +1,202 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{config::Config, protocol::PlayerPosition};
+
+/// Players one recipient gets to see on a tick.
+pub struct Selection {
+    pub visible: HashSet<i32>, // shown at all, at most `max_players`
+    pub fresh: HashSet<i32>,   // the visible ones whose position goes out this tick
+    pub hidden: Vec<i32>,      // were visible last tick, aren't anymore although they're still on the level
+}
+
+/// Decides who sees whom on crowded levels, based on where their first player is.
+/// Nearby players are sent every tick, distant ones every few ticks, and only the closest ones are shown at all.
+pub struct InterestFilter {
+    enabled: bool,
+    radius: u64,
+    far_interval: u64,
+    max_players: usize,
+    visible: HashMap<i32, HashSet<i32>>, // recipient : players it saw on the last tick
+}
+
+impl InterestFilter {
+    pub fn new(config: &Config) -> Self {
+        InterestFilter {
+            enabled: config.interest,
+            radius: config.interest_radius as u64,
+            far_interval: config.interest_far_interval as u64,
+            max_players: config.interest_max_players,
+            visible: HashMap::new(),
+        }
+    }
+
+    pub fn select(&mut self, recipient: i32, players: &HashMap<i32, PlayerPosition>, tick: u64) -> Selection {
+        let others = players.keys().copied().filter(|player_id| *player_id != recipient);
+
+        let Some(me) = players.get(&recipient).filter(|_| self.enabled) else {
+            let visible: HashSet<i32> = others.collect();
+            return Selection {
+                fresh: visible.clone(),
+                visible,
+                hidden: vec![],
+            };
+        };
+
+        let mut by_distance: Vec<(u64, i32)> = others
+            .map(|player_id| (distance_sq(me, &players[&player_id]), player_id))
+            .collect();
+
+        if by_distance.len() > self.max_players {
+            by_distance.select_nth_unstable(self.max_players);
+            by_distance.truncate(self.max_players);
+        }
+
+        let mut selection = Selection {
+            visible: HashSet::new(),
+            fresh: HashSet::new(),
+            hidden: vec![],
+        };
+
+        for (distance_sq, player_id) in by_distance {
+            selection.visible.insert(player_id);
+
+            // distant players are spread over the ticks, so they don't all go out at once
+            let near = distance_sq <= self.radius * self.radius;
+            if near || tick.wrapping_add(player_id as u64).is_multiple_of(self.far_interval) {
+                selection.fresh.insert(player_id);
+            }
+        }
+
+        if let Some(previous) = self.visible.get(&recipient) {
+            selection.hidden = previous
+                .iter()
+                .filter(|player_id| !selection.visible.contains(player_id) && players.contains_key(player_id))
+                .copied()
+                .collect();
+        }
+
+        self.visible.insert(recipient, selection.visible.clone());
+        selection
+    }
+
+    pub fn retain(&mut self, mut keep: impl FnMut(i32) -> bool) {
+        self.visible.retain(|recipient, _| keep(*recipient));
+    }
+}
+
+// positions come from the clients, so players can be anywhere from i32::MIN to i32::MAX.
+// one axis squared still fits, both together saturate
+fn distance_sq(a: &PlayerPosition, b: &PlayerPosition) -> u64 {
+    let dx = a.p1_pos.0.abs_diff(b.p1_pos.0) as u64;
+    let dy = a.p1_pos.1.abs_diff(b.p1_pos.1) as u64;
+    (dx * dx).saturating_add(dy * dy)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(max_players: usize) -> InterestFilter {
+        let config = Config {
+            interest: true,
+            interest_radius: 100,
+            interest_far_interval: 5,
+            interest_max_players: max_players,
+            ..Config::from_env()
+        };
+        InterestFilter::new(&config)
+    }
+
+    fn players(positions: &[(i32, (i32, i32))]) -> HashMap<i32, PlayerPosition> {
+        positions
+            .iter()
+            .map(|(player_id, p1_pos)| {
+                let position = PlayerPosition {
+                    p1_pos: *p1_pos,
+                    ..Default::default()
+                };
+                (*player_id, position)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn only_the_closest_players_are_shown() {
+        let players = players(&[(1, (0, 0)), (2, (10, 0)), (3, (0, -20)), (4, (30, 0))]);
+
+        let selection = filter(2).select(1, &players, 0);
+        assert_eq!(selection.visible, HashSet::from([2, 3]));
+    }
+
+    #[test]
+    fn distant_players_are_sent_every_few_ticks() {
+        let players = players(&[(1, (0, 0)), (2, (50, 50)), (3, (1000, 0))]);
+        let mut filter = filter(10);
+
+        let mut near = 0;
+        let mut far = 0;
+        for tick in 0..10 {
+            let selection = filter.select(1, &players, tick);
+            assert_eq!(selection.visible, HashSet::from([2, 3]));
+            near += selection.fresh.contains(&2) as u32;
+            far += selection.fresh.contains(&3) as u32;
+        }
+
+        assert_eq!(near, 10);
+        assert_eq!(far, 2);
+    }
+
+    #[test]
+    fn extreme_positions_dont_overflow() {
+        let players = players(&[
+            (1, (i32::MIN, i32::MIN)),
+            (2, (i32::MAX, i32::MAX)),
+            (3, (i32::MIN + 1, i32::MIN)),
+            (4, (i32::MAX, i32::MIN)),
+        ]);
+
+        let selection = filter(2).select(1, &players, 0);
+        assert_eq!(selection.visible, HashSet::from([3, 4]));
+        assert_eq!(distance_sq(&players[&1], &players[&2]), u64::MAX);
+    }
+
+    #[test]
+    fn players_pushed_out_are_hidden_once() {
+        let mut filter = filter(1);
+
+        let before = players(&[(1, (0, 0)), (2, (10, 0)), (3, (500, 0))]);
+        assert_eq!(filter.select(1, &before, 0).visible, HashSet::from([2]));
+
+        let after = players(&[(1, (0, 0)), (2, (500, 0)), (3, (10, 0))]);
+        let selection = filter.select(1, &after, 1);
+        assert_eq!(selection.visible, HashSet::from([3]));
+        assert_eq!(selection.hidden, vec![2]);
+        assert!(filter.select(1, &after, 2).hidden.is_empty());
+    }
+
+    #[test]
+    fn forgotten_recipients_start_over() {
+        let mut filter = filter(1);
+
+        let before = players(&[(1, (0, 0)), (2, (10, 0)), (3, (500, 0))]);
+        filter.select(1, &before, 0);
+        filter.retain(|recipient| recipient != 1);
+
+        // nothing to hide, it never saw anyone as far as the filter knows
+        let after = players(&[(1, (0, 0)), (2, (500, 0)), (3, (10, 0))]);
+        assert!(filter.select(1, &after, 1).hidden.is_empty());
+    }
+
+    #[test]
+    fn everyone_is_sent_when_disabled() {
+        let config = Config {
+            interest: false,
+            ..Config::from_env()
+        };
+        let players = players(&[(1, (0, 0)), (2, (10, 0)), (3, (1_000_000, 0))]);
+
+        let selection = InterestFilter::new(&config).select(1, &players, 1);
+        assert_eq!(selection.visible, HashSet::from([2, 3]));
+        assert_eq!(selection.fresh, selection.visible);
+    }
+}
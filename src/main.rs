@@ -1,15 +1,37 @@
-use std::{env, error::Error, sync::Arc};
+use std::{error::Error, sync::Arc};
+
+use colors::ColorProfiles;
+use config::Config;
+use icon_cache::IconCache;
+use icons::IconSheets;
 
 use log::{error, info, LevelFilter};
 use roa::{tcp::Listener, App};
 use state::State;
-use tokio::{net::UdpSocket, sync::Mutex};
+use upstream::UpstreamPool;
 use util::Logger;
+use vip::VipRegistry;
 
+mod buffer_pool;
+mod colors;
+mod config;
+mod cookie;
+mod delta;
+mod dispatch;
 mod gdm_routes;
 mod gdm_server;
+mod icon_cache;
+mod icons;
+mod interest;
+mod outbound;
+mod protocol;
+mod rate_limit;
+mod snapshot;
 mod state;
+mod stats;
+mod upstream;
 mod util;
+mod vip;
 
 static LOGGER: Logger = Logger;
 
@@ -25,18 +47,36 @@ async fn main() -> Result<(), Box<dyn Error>> {
         })
         .unwrap();
 
-    let bind_addr = env::var("BIND_ADDRESS").unwrap_or("0.0.0.0".to_string());
-    let gdm_port = env::var("GDM_PORT").unwrap_or("53790".to_string());
-    let http_port = env::var("HTTP_PORT").unwrap_or("53789".to_string());
+    let config = Config::from_env();
+    let bind_addr = config.bind_address.clone();
+    let http_port = config.http_port.clone();
+
+    let gdm_addr = format!("{bind_addr}:{}", config.gdm_port);
+    let sockets = gdm_server::bind_sockets(&gdm_addr, config.recv_workers)?;
 
-    let gdm_addr = format!("{bind_addr}:{gdm_port}");
-    let socket = Arc::new(UdpSocket::bind(&gdm_addr).await?);
+    let vips = VipRegistry::load(config.vip_file.clone()).await?;
+    let colors = ColorProfiles::load(config.colors_file.clone()).await?;
+    let icon_sheets = IconSheets::load(&config.icons_dir)?;
+    let icon_cache = IconCache::open(config.icon_cache_dir.clone(), config.icon_cache_size).await?;
+    let icon_upstreams = UpstreamPool::new(
+        &config.icon_upstreams,
+        config.icon_connect_timeout,
+        config.icon_read_timeout,
+    )?;
 
-    let state = Arc::new(Mutex::new(State::new(socket.clone())));
+    let state = Arc::new(State::new(
+        sockets[0].clone(),
+        config,
+        vips,
+        colors,
+        icon_sheets,
+        icon_cache,
+        icon_upstreams,
+    ));
     let state_cloned = state.clone();
 
     let handle = tokio::spawn(async move {
-        if let Err(e) = gdm_server::gdm_server(state_cloned, &gdm_addr).await {
+        if let Err(e) = gdm_server::gdm_server(state_cloned, sockets, &gdm_addr).await {
             error!("Error in the server: {}", e);
         }
     });
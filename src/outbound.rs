@@ -0,0 +1,88 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::anyhow;
+use log::debug;
+use tokio::{
+    net::UdpSocket,
+    sync::mpsc::{self, error::TrySendError},
+};
+
+use crate::{config::Config, stats::Stats};
+
+/// Everything the server sends goes through here and out of one sender task. When the queue is full, or a recipient
+/// already has too much queued, new datagrams are dropped instead of holding up the game loop.
+pub struct Outbound {
+    queue: mpsc::Sender<(SocketAddr, Vec<u8>)>,
+    pending: Arc<Mutex<HashMap<SocketAddr, usize>>>, // address : datagrams waiting for it
+    per_recipient: usize,
+    stats: Arc<Stats>,
+}
+
+impl Outbound {
+    pub fn new(socket: Arc<UdpSocket>, config: &Config, stats: Arc<Stats>) -> Self {
+        let (queue, mut datagrams) = mpsc::channel::<(SocketAddr, Vec<u8>)>(config.outbound_queue);
+        let pending: Arc<Mutex<HashMap<SocketAddr, usize>>> = Arc::new(Mutex::new(HashMap::new()));
+
+        let pending_cloned = pending.clone();
+        let stats_cloned = stats.clone();
+        tokio::spawn(async move {
+            while let Some((address, data)) = datagrams.recv().await {
+                release(&pending_cloned, address);
+
+                // one unreachable client only costs its own datagram
+                if let Err(e) = socket.send_to(&data, address).await {
+                    debug!("failed to send to {address}: {e}");
+                    Stats::bump(&stats_cloned.send_failed);
+                }
+            }
+        });
+
+        Outbound {
+            queue,
+            pending,
+            per_recipient: config.outbound_per_recipient,
+            stats,
+        }
+    }
+
+    /// Queues a datagram, or drops it if there's no room for it.
+    pub fn send(&self, address: SocketAddr, data: Vec<u8>) -> anyhow::Result<()> {
+        {
+            let mut pending = self.pending.lock().unwrap();
+            let count = pending.entry(address).or_insert(0);
+            if *count >= self.per_recipient {
+                Stats::bump(&self.stats.send_dropped);
+                return Err(anyhow!("send queue for {address} is full"));
+            }
+            *count += 1;
+        }
+
+        // never queued, so it doesn't count against the recipient
+        match self.queue.try_send((address, data)) {
+            Ok(_) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                release(&self.pending, address);
+                Stats::bump(&self.stats.outbound_full);
+                Err(anyhow!("the send queue is full"))
+            }
+            Err(TrySendError::Closed(_)) => {
+                release(&self.pending, address);
+                Err(anyhow!("the sender task is gone"))
+            }
+        }
+    }
+}
+
+fn release(pending: &Mutex<HashMap<SocketAddr, usize>>, address: SocketAddr) {
+    let mut pending = pending.lock().unwrap();
+    if let Some(count) = pending.get_mut(&address) {
+        *count -= 1;
+        if *count == 0 {
+            pending.remove(&address);
+        }
+    }
+}
@@ -0,0 +1,1533 @@
+use std::fmt;
+
+use bytebuffer::{ByteBuffer, ByteReader, Endian};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Prefixes {
+    Hello = 0x3,
+    Ping = 0x0,
+    Message = 0x1,
+    Disconnect = 0x2,
+    AckHello = 0x4,
+    ServerData = 0x5,
+    PlayerDisconnect = 0x7,
+    PlayerIcons = 0x8,
+    ReceivedPlayerIcons = 0x9,
+    OutsideLevel = 0x10,
+    VipActions = 0x11,
+    BadKey = 0x12,
+    Roam = 0x13,
+    HelloVerify = 0x14,
+    PlayerMessages = 0x15,
+    PlayerDeltas = 0x16,
+    PositionAck = 0x17,
+}
+
+impl Prefixes {
+    pub fn from_number(value: i8) -> Option<Self> {
+        match value {
+            0 => Some(Prefixes::Ping),
+            1 => Some(Prefixes::Message),
+            2 => Some(Prefixes::Disconnect),
+            3 => Some(Prefixes::Hello),
+            4 => Some(Prefixes::AckHello),
+            5 => Some(Prefixes::ServerData),
+            7 => Some(Prefixes::PlayerDisconnect),
+            8 => Some(Prefixes::PlayerIcons),
+            0x9 => Some(Prefixes::ReceivedPlayerIcons),
+            0x10 => Some(Prefixes::OutsideLevel),
+            0x11 => Some(Prefixes::VipActions),
+            0x12 => Some(Prefixes::BadKey),
+            0x13 => Some(Prefixes::Roam),
+            0x14 => Some(Prefixes::HelloVerify),
+            0x15 => Some(Prefixes::PlayerMessages),
+            0x16 => Some(Prefixes::PlayerDeltas),
+            0x17 => Some(Prefixes::PositionAck),
+            _ => None,
+        }
+    }
+
+    pub fn to_number(self) -> i8 {
+        match self {
+            Prefixes::AckHello => 0x4,
+            Prefixes::BadKey => 0x12,
+            Prefixes::Roam => 0x13,
+            Prefixes::HelloVerify => 0x14,
+            Prefixes::PlayerMessages => 0x15,
+            Prefixes::PlayerDeltas => 0x16,
+            Prefixes::PositionAck => 0x17,
+            Prefixes::Disconnect => 0x2,
+            Prefixes::VipActions => 0x11,
+            Prefixes::OutsideLevel => 0x10,
+            Prefixes::ReceivedPlayerIcons => 0x9,
+            Prefixes::PlayerIcons => 0x8,
+            Prefixes::PlayerDisconnect => 0x7,
+            Prefixes::ServerData => 0x5,
+            Prefixes::Hello => 0x3,
+            Prefixes::Ping => 0x0,
+            Prefixes::Message => 0x1,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ProtocolError {
+    Truncated,
+    UnknownPrefix(i8),
+    UnexpectedPrefix { expected: Prefixes, got: Prefixes },
+    InvalidString,
+    UnknownAction(u8),
+    UnknownServerDataKind(u8),
+    UnknownLobbyResult(u8),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::Truncated => write!(f, "packet is truncated"),
+            ProtocolError::UnknownPrefix(prefix) => write!(f, "unknown prefix {prefix:#x}"),
+            ProtocolError::UnexpectedPrefix { expected, got } => {
+                write!(f, "expected {expected:?} packet, got {got:?}")
+            }
+            ProtocolError::InvalidString => write!(f, "string is not valid utf-8"),
+            ProtocolError::UnknownAction(action) => write!(f, "unknown action {action}"),
+            ProtocolError::UnknownServerDataKind(kind) => write!(f, "unknown server data kind {kind}"),
+            ProtocolError::UnknownLobbyResult(result) => write!(f, "unknown lobby result {result}"),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl From<std::io::Error> for ProtocolError {
+    // bytebuffer only fails a read when there's not enough bytes left
+    fn from(_: std::io::Error) -> Self {
+        ProtocolError::Truncated
+    }
+}
+
+pub type Result<T> = std::result::Result<T, ProtocolError>;
+
+/// A single packet of the GDM protocol. Everything on the wire is little endian,
+/// and every packet starts with a one byte `Prefixes` value, which is handled by `encode` and `decode`.
+pub trait Packet: Sized {
+    const PREFIX: Prefixes;
+
+    fn encode_body(&self, buf: &mut ByteBuffer);
+    fn decode_body(reader: &mut ByteReader) -> Result<Self>;
+}
+
+pub fn encode<P: Packet>(packet: &P) -> Vec<u8> {
+    let mut buf = ByteBuffer::new();
+    buf.set_endian(Endian::LittleEndian);
+    buf.write_i8(P::PREFIX.to_number());
+    packet.encode_body(&mut buf);
+    buf.into_vec()
+}
+
+pub fn decode<P: Packet>(data: &[u8]) -> Result<P> {
+    let mut reader = ByteReader::from_bytes(data);
+    reader.set_endian(Endian::LittleEndian);
+
+    let prefix = read_prefix(&mut reader)?;
+    if prefix != P::PREFIX {
+        return Err(ProtocolError::UnexpectedPrefix {
+            expected: P::PREFIX,
+            got: prefix,
+        });
+    }
+
+    P::decode_body(&mut reader)
+}
+
+pub fn peek_prefix(data: &[u8]) -> Result<Prefixes> {
+    let mut reader = ByteReader::from_bytes(data);
+    read_prefix(&mut reader)
+}
+
+fn read_prefix(reader: &mut ByteReader) -> Result<Prefixes> {
+    let prefix = reader.read_i8()?;
+    Prefixes::from_number(prefix).ok_or(ProtocolError::UnknownPrefix(prefix))
+}
+
+fn remaining(reader: &ByteReader) -> usize {
+    reader.len().saturating_sub(reader.get_rpos())
+}
+
+// strings are prefixed with their length as u16, longer ones are cut off at the last character that still fits
+fn write_string(buf: &mut ByteBuffer, value: &str) {
+    let mut len = value.len().min(u16::MAX as usize);
+    while !value.is_char_boundary(len) {
+        len -= 1;
+    }
+
+    buf.write_u16(len as u16);
+    buf.write_bytes(&value.as_bytes()[..len]);
+}
+
+fn read_string(reader: &mut ByteReader) -> Result<String> {
+    let len = reader.read_u16()? as usize;
+    let bytes = reader.read_bytes(len)?;
+    String::from_utf8(bytes).map_err(|_| ProtocolError::InvalidString)
+}
+
+// optional protocol features a client can ask for in its Hello
+pub const FEATURE_BATCHED_POSITIONS: u32 = 1 << 0;
+pub const FEATURE_DELTA_POSITIONS: u32 = 1 << 1;
+pub const FEATURE_CLIENT_RAINBOW: u32 = 1 << 2; // animates rainbow colors from isRainbow.php itself
+pub const FEATURE_SESSION_KEYS: u32 = 1 << 3; // uses the key from AckHello instead of its own, answers roam challenges
+
+pub const COOKIE_LEN: usize = 16;
+pub type Cookie = [u8; COOKIE_LEN];
+
+/// Every packet sent by the client starts with the client ID and the user key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientHeader {
+    pub client_id: i32,
+    pub user_key: u32,
+}
+
+impl ClientHeader {
+    fn encode(&self, buf: &mut ByteBuffer) {
+        buf.write_i32(self.client_id);
+        buf.write_u32(self.user_key);
+    }
+
+    fn decode(reader: &mut ByteReader) -> Result<Self> {
+        Ok(ClientHeader {
+            client_id: reader.read_i32()?,
+            user_key: reader.read_u32()?,
+        })
+    }
+
+    /// Reads only the header of a client packet, skipping the prefix.
+    pub fn peek(data: &[u8]) -> Result<Self> {
+        let mut reader = ByteReader::from_bytes(data);
+        reader.set_endian(Endian::LittleEndian);
+        read_prefix(&mut reader)?;
+        Self::decode(&mut reader)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PlayerPosition {
+    pub p1_pos: (i32, i32),
+    pub p1_rot: (i32, i32),
+    pub p1_gamemode: u8,
+    pub p1_icon: u8,
+    pub p1_size: i32,
+    pub p1_gravity: u8,
+
+    pub p2_pos: (i32, i32),
+    pub p2_rot: (i32, i32),
+    pub p2_gamemode: u8,
+    pub p2_icon: u8,
+    pub p2_size: i32,
+    pub p2_gravity: u8,
+
+    pub is_dead: u8,
+
+    pub color1: u8,
+    pub color2: u8,
+    pub glow: u8,
+
+    pub icon_ids: [u8; 7],
+}
+
+impl PlayerPosition {
+    // both players are laid out the same way on the wire, in both directions
+    fn encode_players(&self, buf: &mut ByteBuffer) {
+        buf.write_i32(self.p1_pos.0);
+        buf.write_i32(self.p1_pos.1);
+        buf.write_i32(self.p1_rot.0);
+        buf.write_i32(self.p1_rot.1);
+        buf.write_u8(self.p1_gamemode);
+        buf.write_u8(self.p1_icon);
+        buf.write_i32(self.p1_size);
+        buf.write_u8(self.p1_gravity);
+
+        buf.write_i32(self.p2_pos.0);
+        buf.write_i32(self.p2_pos.1);
+        buf.write_i32(self.p2_rot.0);
+        buf.write_i32(self.p2_rot.1);
+        buf.write_u8(self.p2_gamemode);
+        buf.write_u8(self.p2_icon);
+        buf.write_i32(self.p2_size);
+        buf.write_u8(self.p2_gravity);
+
+        buf.write_u8(self.is_dead);
+    }
+
+    fn decode_players(reader: &mut ByteReader) -> Result<Self> {
+        Ok(PlayerPosition {
+            p1_pos: (reader.read_i32()?, reader.read_i32()?),
+            p1_rot: (reader.read_i32()?, reader.read_i32()?),
+            p1_gamemode: reader.read_u8()?,
+            p1_icon: reader.read_u8()?,
+            p1_size: reader.read_i32()?,
+            p1_gravity: reader.read_u8()?,
+
+            p2_pos: (reader.read_i32()?, reader.read_i32()?),
+            p2_rot: (reader.read_i32()?, reader.read_i32()?),
+            p2_gamemode: reader.read_u8()?,
+            p2_icon: reader.read_u8()?,
+            p2_size: reader.read_i32()?,
+            p2_gravity: reader.read_u8()?,
+
+            is_dead: reader.read_u8()?,
+
+            // filled in by the caller, those come after the level ID and room
+            color1: 0,
+            color2: 0,
+            glow: 0,
+            icon_ids: [0; 7],
+        })
+    }
+
+    fn encode_looks(&self, buf: &mut ByteBuffer) {
+        buf.write_u8(self.color1);
+        buf.write_u8(self.color2);
+        buf.write_u8(self.glow);
+        buf.write_bytes(&self.icon_ids);
+    }
+
+    fn decode_looks(&mut self, reader: &mut ByteReader) -> Result<()> {
+        self.color1 = reader.read_u8()?;
+        self.color2 = reader.read_u8()?;
+        self.glow = reader.read_u8()?;
+        self.icon_ids = read_icon_ids(reader)?;
+        Ok(())
+    }
+
+    /// Mask of the fields that differ from `base`, one bit per entry of `DELTA_FIELD_SIZES`.
+    pub fn changed_fields(&self, base: &PlayerPosition) -> u32 {
+        let changed = [
+            self.p1_pos != base.p1_pos,
+            self.p1_rot != base.p1_rot,
+            self.p1_gamemode != base.p1_gamemode,
+            self.p1_icon != base.p1_icon,
+            self.p1_size != base.p1_size,
+            self.p1_gravity != base.p1_gravity,
+            self.p2_pos != base.p2_pos,
+            self.p2_rot != base.p2_rot,
+            self.p2_gamemode != base.p2_gamemode,
+            self.p2_icon != base.p2_icon,
+            self.p2_size != base.p2_size,
+            self.p2_gravity != base.p2_gravity,
+            self.is_dead != base.is_dead,
+            self.color1 != base.color1,
+            self.color2 != base.color2,
+            self.glow != base.glow,
+            self.icon_ids != base.icon_ids,
+        ];
+
+        changed
+            .iter()
+            .enumerate()
+            .filter(|(_, changed)| **changed)
+            .fold(0, |mask, (bit, _)| mask | 1 << bit)
+    }
+
+    // only the fields in the mask are written, in the same order as everywhere else
+    fn encode_fields(&self, mask: u32, buf: &mut ByteBuffer) {
+        let has = |bit: u32| mask & (1 << bit) != 0;
+
+        if has(0) {
+            buf.write_i32(self.p1_pos.0);
+            buf.write_i32(self.p1_pos.1);
+        }
+        if has(1) {
+            buf.write_i32(self.p1_rot.0);
+            buf.write_i32(self.p1_rot.1);
+        }
+        if has(2) {
+            buf.write_u8(self.p1_gamemode);
+        }
+        if has(3) {
+            buf.write_u8(self.p1_icon);
+        }
+        if has(4) {
+            buf.write_i32(self.p1_size);
+        }
+        if has(5) {
+            buf.write_u8(self.p1_gravity);
+        }
+        if has(6) {
+            buf.write_i32(self.p2_pos.0);
+            buf.write_i32(self.p2_pos.1);
+        }
+        if has(7) {
+            buf.write_i32(self.p2_rot.0);
+            buf.write_i32(self.p2_rot.1);
+        }
+        if has(8) {
+            buf.write_u8(self.p2_gamemode);
+        }
+        if has(9) {
+            buf.write_u8(self.p2_icon);
+        }
+        if has(10) {
+            buf.write_i32(self.p2_size);
+        }
+        if has(11) {
+            buf.write_u8(self.p2_gravity);
+        }
+        if has(12) {
+            buf.write_u8(self.is_dead);
+        }
+        if has(13) {
+            buf.write_u8(self.color1);
+        }
+        if has(14) {
+            buf.write_u8(self.color2);
+        }
+        if has(15) {
+            buf.write_u8(self.glow);
+        }
+        if has(16) {
+            buf.write_bytes(&self.icon_ids);
+        }
+    }
+
+    // fields missing from the mask are left as they are
+    fn decode_fields(&mut self, mask: u32, reader: &mut ByteReader) -> Result<()> {
+        let has = |bit: u32| mask & (1 << bit) != 0;
+
+        if has(0) {
+            self.p1_pos = (reader.read_i32()?, reader.read_i32()?);
+        }
+        if has(1) {
+            self.p1_rot = (reader.read_i32()?, reader.read_i32()?);
+        }
+        if has(2) {
+            self.p1_gamemode = reader.read_u8()?;
+        }
+        if has(3) {
+            self.p1_icon = reader.read_u8()?;
+        }
+        if has(4) {
+            self.p1_size = reader.read_i32()?;
+        }
+        if has(5) {
+            self.p1_gravity = reader.read_u8()?;
+        }
+        if has(6) {
+            self.p2_pos = (reader.read_i32()?, reader.read_i32()?);
+        }
+        if has(7) {
+            self.p2_rot = (reader.read_i32()?, reader.read_i32()?);
+        }
+        if has(8) {
+            self.p2_gamemode = reader.read_u8()?;
+        }
+        if has(9) {
+            self.p2_icon = reader.read_u8()?;
+        }
+        if has(10) {
+            self.p2_size = reader.read_i32()?;
+        }
+        if has(11) {
+            self.p2_gravity = reader.read_u8()?;
+        }
+        if has(12) {
+            self.is_dead = reader.read_u8()?;
+        }
+        if has(13) {
+            self.color1 = reader.read_u8()?;
+        }
+        if has(14) {
+            self.color2 = reader.read_u8()?;
+        }
+        if has(15) {
+            self.glow = reader.read_u8()?;
+        }
+        if has(16) {
+            self.icon_ids = read_icon_ids(reader)?;
+        }
+        Ok(())
+    }
+}
+
+// sizes of the fields in delta updates, in mask bit order
+const DELTA_FIELD_SIZES: [usize; 17] = [8, 8, 1, 1, 4, 1, 8, 8, 1, 1, 4, 1, 1, 1, 1, 1, 7];
+pub const DELTA_ALL_FIELDS: u32 = (1 << DELTA_FIELD_SIZES.len()) - 1;
+
+fn read_icon_ids(reader: &mut ByteReader) -> Result<[u8; 7]> {
+    let mut icon_ids = [0u8; 7];
+    icon_ids.copy_from_slice(&reader.read_bytes(7)?);
+    Ok(icon_ids)
+}
+
+/* Client -> server */
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hello {
+    pub header: ClientHeader,
+    pub cookie: Option<Cookie>, // only sent by OpenGDM clients, all zeroes until the server hands one out
+    pub features: u32,          // FEATURE_* flags, OpenGDM clients send them after the cookie
+}
+
+impl Packet for Hello {
+    const PREFIX: Prefixes = Prefixes::Hello;
+
+    fn encode_body(&self, buf: &mut ByteBuffer) {
+        self.header.encode(buf);
+        if let Some(cookie) = &self.cookie {
+            buf.write_bytes(cookie);
+            buf.write_u32(self.features);
+        } else if self.features != 0 {
+            buf.write_u32(self.features);
+        }
+    }
+
+    fn decode_body(reader: &mut ByteReader) -> Result<Self> {
+        let header = ClientHeader::decode(reader)?;
+
+        // what follows the header is told apart by its length: nothing for stock GDM, 4 bytes of features,
+        // or a cookie with or without features after it (older OpenGDM clients don't send them)
+        let cookie = if remaining(reader) >= COOKIE_LEN {
+            let mut cookie = [0u8; COOKIE_LEN];
+            cookie.copy_from_slice(&reader.read_bytes(COOKIE_LEN)?);
+            Some(cookie)
+        } else {
+            None
+        };
+
+        let features = if remaining(reader) >= 4 { reader.read_u32()? } else { 0 };
+
+        Ok(Hello {
+            header,
+            cookie,
+            features,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ping {
+    pub header: ClientHeader,
+    // contents are unknown, but the client always sends 20 bytes
+    pub payload: [u8; 20],
+}
+
+impl Packet for Ping {
+    const PREFIX: Prefixes = Prefixes::Ping;
+
+    fn encode_body(&self, buf: &mut ByteBuffer) {
+        self.header.encode(buf);
+        buf.write_bytes(&self.payload);
+    }
+
+    fn decode_body(reader: &mut ByteReader) -> Result<Self> {
+        let header = ClientHeader::decode(reader)?;
+        let mut payload = [0u8; 20];
+        payload.copy_from_slice(&reader.read_bytes(20)?);
+        Ok(Ping { header, payload })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Disconnect {
+    pub header: ClientHeader,
+}
+
+impl Packet for Disconnect {
+    const PREFIX: Prefixes = Prefixes::Disconnect;
+
+    fn encode_body(&self, buf: &mut ByteBuffer) {
+        self.header.encode(buf);
+    }
+
+    fn decode_body(reader: &mut ByteReader) -> Result<Self> {
+        Ok(Disconnect {
+            header: ClientHeader::decode(reader)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutsideLevel {
+    pub header: ClientHeader,
+}
+
+impl Packet for OutsideLevel {
+    const PREFIX: Prefixes = Prefixes::OutsideLevel;
+
+    fn encode_body(&self, buf: &mut ByteBuffer) {
+        self.header.encode(buf);
+    }
+
+    fn decode_body(reader: &mut ByteReader) -> Result<Self> {
+        Ok(OutsideLevel {
+            header: ClientHeader::decode(reader)?,
+        })
+    }
+}
+
+/// Position update sent by the client, level ID of -1 means the client is not in a level.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub header: ClientHeader,
+    pub level_id: i32,
+    pub room: i16,
+    pub position: PlayerPosition,
+    pub sequence: Option<u32>, // appended by clients with FEATURE_DELTA_POSITIONS
+}
+
+impl Packet for Message {
+    const PREFIX: Prefixes = Prefixes::Message;
+
+    fn encode_body(&self, buf: &mut ByteBuffer) {
+        self.header.encode(buf);
+        self.position.encode_players(buf);
+        buf.write_i32(self.level_id);
+        buf.write_i16(self.room);
+        self.position.encode_looks(buf);
+        if let Some(sequence) = self.sequence {
+            buf.write_u32(sequence);
+        }
+    }
+
+    fn decode_body(reader: &mut ByteReader) -> Result<Self> {
+        let header = ClientHeader::decode(reader)?;
+        let mut position = PlayerPosition::decode_players(reader)?;
+        let level_id = reader.read_i32()?;
+        let room = reader.read_i16()?;
+        position.decode_looks(reader)?;
+        let sequence = if remaining(reader) >= 4 {
+            Some(reader.read_u32()?)
+        } else {
+            None
+        };
+
+        Ok(Message {
+            header,
+            level_id,
+            room,
+            position,
+            sequence,
+        })
+    }
+}
+
+/// Answer to `RoamChallenge`, sent from the new address to move the session there.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoamResponse {
+    pub header: ClientHeader,
+    pub nonce: u32,
+}
+
+impl Packet for RoamResponse {
+    const PREFIX: Prefixes = Prefixes::Roam;
+
+    fn encode_body(&self, buf: &mut ByteBuffer) {
+        self.header.encode(buf);
+        buf.write_u32(self.nonce);
+    }
+
+    fn decode_body(reader: &mut ByteReader) -> Result<Self> {
+        Ok(RoamResponse {
+            header: ClientHeader::decode(reader)?,
+            nonce: reader.read_u32()?,
+        })
+    }
+}
+
+/// Request for the icons of another player.
+#[allow(dead_code)] // never sent by any client we know of, the layout is kept for when one does
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlayerIcons {
+    pub header: ClientHeader,
+    pub player_id: i32,
+}
+
+impl Packet for PlayerIcons {
+    const PREFIX: Prefixes = Prefixes::PlayerIcons;
+
+    fn encode_body(&self, buf: &mut ByteBuffer) {
+        self.header.encode(buf);
+        buf.write_i32(self.player_id);
+    }
+
+    fn decode_body(reader: &mut ByteReader) -> Result<Self> {
+        Ok(PlayerIcons {
+            header: ClientHeader::decode(reader)?,
+            player_id: reader.read_i32()?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LobbyAction {
+    Create { name: String },
+    Join,
+    Leave,
+}
+
+/// Lobby management. Stock clients send nothing but the VIP key and the lobby code (7 bytes with the prefix),
+/// which means `LobbyAction::Create` and leaves `header` empty. OpenGDM clients put their header in front
+/// and an action after the code. The VIP key is the session key of the client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VipActions {
+    pub header: Option<ClientHeader>,
+    pub vip_key: u32,
+    pub lobby_code: u16,
+    pub action: LobbyAction,
+}
+
+// vip key and lobby code
+const STOCK_VIP_ACTIONS_LEN: usize = 6;
+
+impl Packet for VipActions {
+    const PREFIX: Prefixes = Prefixes::VipActions;
+
+    fn encode_body(&self, buf: &mut ByteBuffer) {
+        let Some(header) = &self.header else {
+            buf.write_u32(self.vip_key);
+            buf.write_u16(self.lobby_code);
+            return;
+        };
+
+        header.encode(buf);
+        buf.write_u32(self.vip_key);
+        buf.write_u16(self.lobby_code);
+        match &self.action {
+            LobbyAction::Create { name } => {
+                buf.write_u8(0);
+                write_string(buf, name);
+            }
+            LobbyAction::Join => buf.write_u8(1),
+            LobbyAction::Leave => buf.write_u8(2),
+        }
+    }
+
+    fn decode_body(reader: &mut ByteReader) -> Result<Self> {
+        if remaining(reader) == STOCK_VIP_ACTIONS_LEN {
+            return Ok(VipActions {
+                header: None,
+                vip_key: reader.read_u32()?,
+                lobby_code: reader.read_u16()?,
+                action: LobbyAction::Create {
+                    name: String::new(),
+                },
+            });
+        }
+
+        let header = ClientHeader::decode(reader)?;
+        let vip_key = reader.read_u32()?;
+        let lobby_code = reader.read_u16()?;
+
+        let action = if remaining(reader) == 0 {
+            LobbyAction::Create {
+                name: String::new(),
+            }
+        } else {
+            match reader.read_u8()? {
+                0 => LobbyAction::Create {
+                    name: read_string(reader)?,
+                },
+                1 => LobbyAction::Join,
+                2 => LobbyAction::Leave,
+                other => return Err(ProtocolError::UnknownAction(other)),
+            }
+        };
+
+        Ok(VipActions {
+            header: Some(header),
+            vip_key,
+            lobby_code,
+            action,
+        })
+    }
+}
+
+/// Sent by clients with `FEATURE_DELTA_POSITIONS` once every part of a `PlayerDeltas` update arrived.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PositionAck {
+    pub header: ClientHeader,
+    pub sequence: u32,
+}
+
+impl Packet for PositionAck {
+    const PREFIX: Prefixes = Prefixes::PositionAck;
+
+    fn encode_body(&self, buf: &mut ByteBuffer) {
+        self.header.encode(buf);
+        buf.write_u32(self.sequence);
+    }
+
+    fn decode_body(reader: &mut ByteReader) -> Result<Self> {
+        Ok(PositionAck {
+            header: ClientHeader::decode(reader)?,
+            sequence: reader.read_u32()?,
+        })
+    }
+}
+
+/* Server -> client */
+
+/// Accepts the `Hello`. The client has to use `session_key` as its user key from then on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AckHello {
+    pub session_key: u32,
+}
+
+impl Packet for AckHello {
+    const PREFIX: Prefixes = Prefixes::AckHello;
+
+    fn encode_body(&self, buf: &mut ByteBuffer) {
+        buf.write_u32(self.session_key);
+    }
+
+    fn decode_body(reader: &mut ByteReader) -> Result<Self> {
+        Ok(AckHello {
+            session_key: reader.read_u32()?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PingResponse {
+    pub online_players: i32,
+}
+
+impl Packet for PingResponse {
+    const PREFIX: Prefixes = Prefixes::Ping;
+
+    fn encode_body(&self, buf: &mut ByteBuffer) {
+        buf.write_i32(self.online_players);
+    }
+
+    fn decode_body(reader: &mut ByteReader) -> Result<Self> {
+        Ok(PingResponse {
+            online_players: reader.read_i32()?,
+        })
+    }
+}
+
+/// Disconnect sent by the server, the reason is raw ascii until the end of the packet.
+#[allow(dead_code)] // nobody gets kicked yet
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Kick {
+    pub reason: String,
+}
+
+impl Packet for Kick {
+    const PREFIX: Prefixes = Prefixes::Disconnect;
+
+    fn encode_body(&self, buf: &mut ByteBuffer) {
+        buf.write_bytes(self.reason.as_bytes());
+    }
+
+    fn decode_body(reader: &mut ByteReader) -> Result<Self> {
+        let len = remaining(reader);
+        let bytes = reader.read_bytes(len)?;
+        Ok(Kick {
+            reason: String::from_utf8(bytes).map_err(|_| ProtocolError::InvalidString)?,
+        })
+    }
+}
+
+/// Answer to a `Hello` without a valid cookie, the client has to say hello again with this cookie.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HelloVerify {
+    pub cookie: Cookie,
+}
+
+impl Packet for HelloVerify {
+    const PREFIX: Prefixes = Prefixes::HelloVerify;
+
+    fn encode_body(&self, buf: &mut ByteBuffer) {
+        buf.write_bytes(&self.cookie);
+    }
+
+    fn decode_body(reader: &mut ByteReader) -> Result<Self> {
+        let mut cookie = [0u8; COOKIE_LEN];
+        cookie.copy_from_slice(&reader.read_bytes(COOKIE_LEN)?);
+        Ok(HelloVerify { cookie })
+    }
+}
+
+/// Sent to a new address that used a valid session key, the client has to echo the nonce back from there.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoamChallenge {
+    pub nonce: u32,
+}
+
+impl Packet for RoamChallenge {
+    const PREFIX: Prefixes = Prefixes::Roam;
+
+    fn encode_body(&self, buf: &mut ByteBuffer) {
+        buf.write_u32(self.nonce);
+    }
+
+    fn decode_body(reader: &mut ByteReader) -> Result<Self> {
+        Ok(RoamChallenge {
+            nonce: reader.read_u32()?,
+        })
+    }
+}
+
+/// Position of another player on the same level.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlayerMessage {
+    pub player_id: i32,
+    pub position: PlayerPosition,
+}
+
+impl Packet for PlayerMessage {
+    const PREFIX: Prefixes = Prefixes::Message;
+
+    fn encode_body(&self, buf: &mut ByteBuffer) {
+        buf.write_i32(self.player_id);
+        self.position.encode_players(buf);
+        buf.write_u8(0u8); // ActiveIconId - unknown, unused?
+        self.position.encode_looks(buf);
+    }
+
+    fn decode_body(reader: &mut ByteReader) -> Result<Self> {
+        let player_id = reader.read_i32()?;
+        let mut position = PlayerPosition::decode_players(reader)?;
+        reader.read_u8()?;
+        position.decode_looks(reader)?;
+
+        Ok(PlayerMessage {
+            player_id,
+            position,
+        })
+    }
+}
+
+/// Positions of several players in one datagram, for clients with `FEATURE_BATCHED_POSITIONS`.
+/// A count, then every entry laid out like a `PlayerMessage` without its prefix.
+#[allow(dead_code)] // built straight from encoded messages by `batch_player_messages`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlayerMessages {
+    pub messages: Vec<PlayerMessage>,
+}
+
+impl Packet for PlayerMessages {
+    const PREFIX: Prefixes = Prefixes::PlayerMessages;
+
+    fn encode_body(&self, buf: &mut ByteBuffer) {
+        buf.write_u8(self.messages.len() as u8);
+        for message in self.messages.iter() {
+            message.encode_body(buf);
+        }
+    }
+
+    fn decode_body(reader: &mut ByteReader) -> Result<Self> {
+        let count = reader.read_u8()?;
+        let mut messages = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            messages.push(PlayerMessage::decode_body(reader)?);
+        }
+
+        Ok(PlayerMessages { messages })
+    }
+}
+
+/// Packs already encoded `PlayerMessage` packets into `PlayerMessages` datagrams of at most `mtu` bytes,
+/// so every position only has to be encoded once no matter how many players it goes to.
+pub fn batch_player_messages<'a>(messages: impl Iterator<Item = &'a [u8]>, mtu: usize) -> Vec<Vec<u8>> {
+    let mut batches = vec![];
+    let mut batch: Vec<u8> = vec![];
+
+    for message in messages {
+        // entries are the messages without their prefix
+        let entry = &message[1..];
+
+        // the count is a single byte
+        if !batch.is_empty() && (batch.len() + entry.len() > mtu || batch[1] == u8::MAX) {
+            batches.push(std::mem::take(&mut batch));
+        }
+
+        if batch.is_empty() {
+            batch.push(Prefixes::PlayerMessages.to_number() as u8);
+            batch.push(0);
+        }
+
+        batch.extend_from_slice(entry);
+        batch[1] += 1;
+    }
+
+    if !batch.is_empty() {
+        batches.push(batch);
+    }
+
+    batches
+}
+
+/// Changed fields of one player, only the fields in `changed` are meaningful.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PositionDelta {
+    pub player_id: i32,
+    pub changed: u32,
+    pub position: PlayerPosition,
+}
+
+impl PositionDelta {
+    pub fn encoded_len(&self) -> usize {
+        let fields: usize = DELTA_FIELD_SIZES
+            .iter()
+            .enumerate()
+            .filter(|(bit, _)| self.changed & (1 << bit) != 0)
+            .map(|(_, size)| size)
+            .sum();
+
+        4 + 4 + fields
+    }
+}
+
+/// One part of a delta update for clients with `FEATURE_DELTA_POSITIONS`. The deltas are against the update
+/// numbered `baseline` (0 when they're all sent in full), and an update can be split into several parts to fit the MTU.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlayerDeltas {
+    pub sequence: u32,
+    pub baseline: u32,
+    pub part: u8,
+    pub parts: u8,
+    pub deltas: Vec<PositionDelta>,
+}
+
+impl PlayerDeltas {
+    // prefix, sequence, baseline, part, parts and the count
+    pub const HEADER_LEN: usize = 1 + 4 + 4 + 1 + 1 + 1;
+}
+
+impl Packet for PlayerDeltas {
+    const PREFIX: Prefixes = Prefixes::PlayerDeltas;
+
+    fn encode_body(&self, buf: &mut ByteBuffer) {
+        buf.write_u32(self.sequence);
+        buf.write_u32(self.baseline);
+        buf.write_u8(self.part);
+        buf.write_u8(self.parts);
+        buf.write_u8(self.deltas.len() as u8);
+        for delta in self.deltas.iter() {
+            buf.write_i32(delta.player_id);
+            buf.write_u32(delta.changed);
+            delta.position.encode_fields(delta.changed, buf);
+        }
+    }
+
+    fn decode_body(reader: &mut ByteReader) -> Result<Self> {
+        let sequence = reader.read_u32()?;
+        let baseline = reader.read_u32()?;
+        let part = reader.read_u8()?;
+        let parts = reader.read_u8()?;
+
+        let count = reader.read_u8()?;
+        let mut deltas = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let player_id = reader.read_i32()?;
+            let changed = reader.read_u32()?;
+            let mut position = PlayerPosition::default();
+            position.decode_fields(changed, reader)?;
+            deltas.push(PositionDelta {
+                player_id,
+                changed,
+                position,
+            });
+        }
+
+        Ok(PlayerDeltas {
+            sequence,
+            baseline,
+            part,
+            parts,
+            deltas,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlayerDisconnect {
+    pub player_id: i32,
+}
+
+impl Packet for PlayerDisconnect {
+    const PREFIX: Prefixes = Prefixes::PlayerDisconnect;
+
+    fn encode_body(&self, buf: &mut ByteBuffer) {
+        buf.write_i32(self.player_id);
+    }
+
+    fn decode_body(reader: &mut ByteReader) -> Result<Self> {
+        Ok(PlayerDisconnect {
+            player_id: reader.read_i32()?,
+        })
+    }
+}
+
+#[allow(dead_code)] // answer to `PlayerIcons`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReceivedPlayerIcons {
+    pub player_id: i32,
+    pub color1: u8,
+    pub color2: u8,
+    pub glow: u8,
+    pub icon_ids: [u8; 7],
+}
+
+impl Packet for ReceivedPlayerIcons {
+    const PREFIX: Prefixes = Prefixes::ReceivedPlayerIcons;
+
+    fn encode_body(&self, buf: &mut ByteBuffer) {
+        buf.write_i32(self.player_id);
+        buf.write_u8(self.color1);
+        buf.write_u8(self.color2);
+        buf.write_u8(self.glow);
+        buf.write_bytes(&self.icon_ids);
+    }
+
+    fn decode_body(reader: &mut ByteReader) -> Result<Self> {
+        Ok(ReceivedPlayerIcons {
+            player_id: reader.read_i32()?,
+            color1: reader.read_u8()?,
+            color2: reader.read_u8()?,
+            glow: reader.read_u8()?,
+            icon_ids: read_icon_ids(reader)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServerDataKind {
+    Motd = 0,
+    Announcement = 1,
+    LevelNotice = 2,
+}
+
+impl ServerDataKind {
+    fn from_number(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(ServerDataKind::Motd),
+            1 => Some(ServerDataKind::Announcement),
+            2 => Some(ServerDataKind::LevelNotice),
+            _ => None,
+        }
+    }
+}
+
+/// Text pushed by the server, for the client to show to the player.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerData {
+    pub kind: ServerDataKind,
+    pub message: String,
+}
+
+impl Packet for ServerData {
+    const PREFIX: Prefixes = Prefixes::ServerData;
+
+    fn encode_body(&self, buf: &mut ByteBuffer) {
+        buf.write_u8(self.kind as u8);
+        write_string(buf, &self.message);
+    }
+
+    fn decode_body(reader: &mut ByteReader) -> Result<Self> {
+        let kind = reader.read_u8()?;
+        Ok(ServerData {
+            kind: ServerDataKind::from_number(kind).ok_or(ProtocolError::UnknownServerDataKind(kind))?,
+            message: read_string(reader)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BadKey {
+    pub reason: String,
+}
+
+impl Packet for BadKey {
+    const PREFIX: Prefixes = Prefixes::BadKey;
+
+    fn encode_body(&self, buf: &mut ByteBuffer) {
+        write_string(buf, &self.reason);
+    }
+
+    fn decode_body(reader: &mut ByteReader) -> Result<Self> {
+        Ok(BadKey {
+            reason: read_string(reader)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LobbyResult {
+    Ok = 0,
+    CodeTaken = 1,
+    NotFound = 2,
+    NotVip = 3,
+    InvalidCode = 4,
+}
+
+impl LobbyResult {
+    fn from_number(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(LobbyResult::Ok),
+            1 => Some(LobbyResult::CodeTaken),
+            2 => Some(LobbyResult::NotFound),
+            3 => Some(LobbyResult::NotVip),
+            4 => Some(LobbyResult::InvalidCode),
+            _ => None,
+        }
+    }
+}
+
+/// Answer to `VipActions`, tells the client whether the lobby action went through.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VipActionsResult {
+    pub lobby_code: u16,
+    pub result: LobbyResult,
+}
+
+impl Packet for VipActionsResult {
+    const PREFIX: Prefixes = Prefixes::VipActions;
+
+    fn encode_body(&self, buf: &mut ByteBuffer) {
+        buf.write_u16(self.lobby_code);
+        buf.write_u8(self.result as u8);
+    }
+
+    fn decode_body(reader: &mut ByteReader) -> Result<Self> {
+        let lobby_code = reader.read_u16()?;
+        let result = reader.read_u8()?;
+
+        Ok(VipActionsResult {
+            lobby_code,
+            result: LobbyResult::from_number(result).ok_or(ProtocolError::UnknownLobbyResult(result))?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER: ClientHeader = ClientHeader {
+        client_id: 48151623,
+        user_key: 0xdeadbeef,
+    };
+
+    // every field different, so a field read in the wrong order shows up
+    fn position() -> PlayerPosition {
+        PlayerPosition {
+            p1_pos: (1000, -2000),
+            p1_rot: (3, -4),
+            p1_gamemode: 5,
+            p1_icon: 6,
+            p1_size: 7,
+            p1_gravity: 8,
+            p2_pos: (9000, -10000),
+            p2_rot: (11, -12),
+            p2_gamemode: 13,
+            p2_icon: 14,
+            p2_size: 15,
+            p2_gravity: 16,
+            is_dead: 1,
+            color1: 17,
+            color2: 18,
+            glow: 19,
+            icon_ids: [20, 21, 22, 23, 24, 25, 26],
+        }
+    }
+
+    fn round_trip<P: Packet + PartialEq + fmt::Debug>(packet: P) {
+        let data = encode(&packet);
+        assert_eq!(peek_prefix(&data).unwrap(), P::PREFIX);
+        assert_eq!(decode::<P>(&data).unwrap(), packet);
+    }
+
+    #[test]
+    fn client_packets_round_trip() {
+        round_trip(Hello {
+            header: HEADER,
+            cookie: None,
+            features: 0,
+        });
+        round_trip(Hello {
+            header: HEADER,
+            cookie: Some([7; COOKIE_LEN]),
+            features: FEATURE_BATCHED_POSITIONS | FEATURE_DELTA_POSITIONS,
+        });
+        round_trip(Hello {
+            header: HEADER,
+            cookie: None,
+            features: FEATURE_SESSION_KEYS,
+        });
+        round_trip(Ping {
+            header: HEADER,
+            payload: [9; 20],
+        });
+        round_trip(Disconnect { header: HEADER });
+        round_trip(OutsideLevel { header: HEADER });
+        round_trip(Message {
+            header: HEADER,
+            level_id: 128,
+            room: 4321,
+            position: position(),
+            sequence: None,
+        });
+        round_trip(Message {
+            header: HEADER,
+            level_id: -1,
+            room: 0,
+            position: position(),
+            sequence: Some(u32::MAX),
+        });
+        round_trip(RoamResponse {
+            header: HEADER,
+            nonce: 0x12345678,
+        });
+        round_trip(PlayerIcons {
+            header: HEADER,
+            player_id: 42,
+        });
+        round_trip(PositionAck {
+            header: HEADER,
+            sequence: 77,
+        });
+        for action in [
+            LobbyAction::Create {
+                name: "my lobby ✨".to_string(),
+            },
+            LobbyAction::Join,
+            LobbyAction::Leave,
+        ] {
+            round_trip(VipActions {
+                header: Some(HEADER),
+                vip_key: 99,
+                lobby_code: 1234,
+                action,
+            });
+        }
+    }
+
+    #[test]
+    fn server_packets_round_trip() {
+        round_trip(AckHello { session_key: 31337 });
+        round_trip(PingResponse { online_players: 250 });
+        round_trip(Kick {
+            reason: "bye".to_string(),
+        });
+        round_trip(HelloVerify { cookie: [3; COOKIE_LEN] });
+        round_trip(RoamChallenge { nonce: 0xcafe });
+        round_trip(PlayerMessage {
+            player_id: 42,
+            position: position(),
+        });
+        round_trip(PlayerMessages {
+            messages: vec![
+                PlayerMessage {
+                    player_id: 1,
+                    position: position(),
+                },
+                PlayerMessage {
+                    player_id: 2,
+                    position: PlayerPosition::default(),
+                },
+            ],
+        });
+        round_trip(PlayerDisconnect { player_id: 42 });
+        round_trip(ReceivedPlayerIcons {
+            player_id: 42,
+            color1: 1,
+            color2: 2,
+            glow: 1,
+            icon_ids: [1, 2, 3, 4, 5, 6, 7],
+        });
+        for kind in [ServerDataKind::Motd, ServerDataKind::Announcement, ServerDataKind::LevelNotice] {
+            round_trip(ServerData {
+                kind,
+                message: "hello there".to_string(),
+            });
+        }
+        round_trip(BadKey {
+            reason: "wrong key".to_string(),
+        });
+        for result in [
+            LobbyResult::Ok,
+            LobbyResult::CodeTaken,
+            LobbyResult::NotFound,
+            LobbyResult::NotVip,
+            LobbyResult::InvalidCode,
+        ] {
+            round_trip(VipActionsResult {
+                lobby_code: 1234,
+                result,
+            });
+        }
+    }
+
+    #[test]
+    fn player_deltas_round_trip() {
+        // fields outside the mask aren't sent, so they decode as defaults
+        let partial = PlayerPosition {
+            p1_pos: (5, 6),
+            icon_ids: [1; 7],
+            ..Default::default()
+        };
+
+        round_trip(PlayerDeltas {
+            sequence: 10,
+            baseline: 7,
+            part: 1,
+            parts: 3,
+            deltas: vec![
+                PositionDelta {
+                    player_id: 1,
+                    changed: DELTA_ALL_FIELDS,
+                    position: position(),
+                },
+                PositionDelta {
+                    player_id: 2,
+                    changed: 0,
+                    position: PlayerPosition::default(),
+                },
+                PositionDelta {
+                    player_id: 3,
+                    changed: partial.changed_fields(&PlayerPosition::default()),
+                    position: partial,
+                },
+            ],
+        });
+    }
+
+    #[test]
+    fn encoded_len_matches_encoding() {
+        let delta = PositionDelta {
+            player_id: 1,
+            changed: position().changed_fields(&PlayerPosition::default()),
+            position: position(),
+        };
+        assert_eq!(delta.changed, DELTA_ALL_FIELDS);
+
+        let packet = PlayerDeltas {
+            sequence: 1,
+            baseline: 0,
+            part: 0,
+            parts: 1,
+            deltas: vec![delta.clone()],
+        };
+        assert_eq!(encode(&packet).len(), PlayerDeltas::HEADER_LEN + delta.encoded_len());
+    }
+
+    fn player_message(player_id: i32) -> Vec<u8> {
+        encode(&PlayerMessage {
+            player_id,
+            position: position(),
+        })
+    }
+
+    #[test]
+    fn batches_are_split_at_the_mtu() {
+        let messages: Vec<_> = (0..10).map(player_message).collect();
+        let entry_len = messages[0].len() - 1;
+        let mtu = 2 + entry_len * 4;
+
+        let batches = batch_player_messages(messages.iter().map(|m| m.as_slice()), mtu);
+        assert_eq!(batches.iter().map(|b| b[1]).collect::<Vec<_>>(), vec![4, 4, 2]);
+        assert!(batches.iter().all(|b| b.len() <= mtu));
+
+        let ids: Vec<i32> = batches
+            .iter()
+            .flat_map(|b| decode::<PlayerMessages>(b).unwrap().messages)
+            .map(|m| m.player_id)
+            .collect();
+        assert_eq!(ids, (0..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn batches_hold_at_most_255_entries() {
+        let messages: Vec<_> = (0..300).map(player_message).collect();
+
+        let batches = batch_player_messages(messages.iter().map(|m| m.as_slice()), usize::MAX);
+        assert_eq!(batches.iter().map(|b| b[1]).collect::<Vec<_>>(), vec![255, 45]);
+        assert_eq!(decode::<PlayerMessages>(&batches[0]).unwrap().messages.len(), 255);
+    }
+
+    #[test]
+    fn truncated_packets_fail() {
+        let data = encode(&Message {
+            header: HEADER,
+            level_id: 128,
+            room: 0,
+            position: position(),
+            sequence: None,
+        });
+
+        for len in [1, 5, 20, data.len() - 1] {
+            assert!(matches!(decode::<Message>(&data[..len]), Err(ProtocolError::Truncated)));
+        }
+        assert!(matches!(decode::<Message>(&[]), Err(ProtocolError::Truncated)));
+        assert!(matches!(ClientHeader::peek(&data[..4]), Err(ProtocolError::Truncated)));
+    }
+
+    #[test]
+    fn stock_vip_actions_create_a_lobby() {
+        let data = [0x11, 99, 0, 0, 0, 0xd2, 0x04];
+        let packet: VipActions = decode(&data).unwrap();
+        assert_eq!(
+            packet,
+            VipActions {
+                header: None,
+                vip_key: 99,
+                lobby_code: 1234,
+                action: LobbyAction::Create {
+                    name: String::new(),
+                },
+            }
+        );
+        assert_eq!(encode(&packet), data);
+    }
+
+    #[test]
+    fn unknown_prefix_fails() {
+        let mut data = encode(&Disconnect { header: HEADER });
+        data[0] = 0x7f;
+
+        assert!(matches!(peek_prefix(&data), Err(ProtocolError::UnknownPrefix(0x7f))));
+        assert!(matches!(decode::<Disconnect>(&data), Err(ProtocolError::UnknownPrefix(0x7f))));
+    }
+
+    #[test]
+    fn wrong_prefix_fails() {
+        let data = encode(&Disconnect { header: HEADER });
+        assert!(matches!(
+            decode::<OutsideLevel>(&data),
+            Err(ProtocolError::UnexpectedPrefix {
+                expected: Prefixes::OutsideLevel,
+                got: Prefixes::Disconnect,
+            })
+        ));
+    }
+
+    #[test]
+    fn unknown_enum_values_fail() {
+        let mut data = encode(&ServerData {
+            kind: ServerDataKind::Motd,
+            message: String::new(),
+        });
+        data[1] = 200;
+        assert!(matches!(decode::<ServerData>(&data), Err(ProtocolError::UnknownServerDataKind(200))));
+
+        let mut data = encode(&VipActionsResult {
+            lobby_code: 1,
+            result: LobbyResult::Ok,
+        });
+        data[3] = 200;
+        assert!(matches!(decode::<VipActionsResult>(&data), Err(ProtocolError::UnknownLobbyResult(200))));
+    }
+
+    #[test]
+    fn long_strings_are_cut_at_a_character() {
+        // two bytes per character, so u16::MAX falls in the middle of one
+        let reason = "é".repeat(40000);
+        let data = encode(&BadKey { reason: reason.clone() });
+
+        let decoded: BadKey = decode(&data).unwrap();
+        assert_eq!(decoded.reason.len(), u16::MAX as usize - 1);
+        assert!(reason.starts_with(&decoded.reason));
+    }
+}
@@ -0,0 +1,413 @@
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    hash::BuildHasher,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use log::{debug, warn};
+
+use crate::{
+    config::Config,
+    protocol::{self, ClientHeader, Prefixes},
+    stats::Stats,
+};
+
+// several players can share an address behind NAT, so the per-address budget is this many clients' worth
+const ADDRESS_MULTIPLIER: f64 = 8.0;
+// drops within this window count towards a ban
+const STRIKE_WINDOW: Duration = Duration::from_secs(10);
+// idle buckets are forgotten after this long
+const PRUNE_INTERVAL: Duration = Duration::from_secs(30);
+// receive workers only wait for each other when their packets come from addresses in the same shard
+const LIMITER_SHARDS: usize = 16;
+
+#[derive(Clone, Copy)]
+struct Budget {
+    rate: f64, // tokens per second
+    burst: f64,
+}
+
+struct TokenBucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(budget: Budget) -> Self {
+        TokenBucket {
+            tokens: budget.burst,
+            last: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, budget: Budget, now: Instant) {
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * budget.rate).min(budget.burst);
+        self.last = now;
+    }
+
+    fn has_token(&self) -> bool {
+        self.tokens >= 1.0
+    }
+
+    fn take(&mut self, budget: Budget, now: Instant) -> bool {
+        self.refill(budget, now);
+        if self.has_token() {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Token buckets per source address and per client, with a separate budget for every packet type.
+/// Runs in the receive loop before a task is spawned, so dropped packets cost next to nothing.
+pub struct RateLimiter {
+    enabled: bool,
+    message_rate: f64,
+    ack_rate: f64,
+    ban_after: u32,
+    ban_duration: Duration,
+    // `None` is for packets too short or unknown to even have a prefix
+    by_address: HashMap<(IpAddr, Option<Prefixes>), TokenBucket>,
+    // client IDs aren't authenticated yet at this point, so they're only trusted together with the address.
+    // otherwise anyone could drain someone else's budget by sending packets with their ID
+    by_client: HashMap<(IpAddr, i32, Prefixes), TokenBucket>,
+    strikes: HashMap<IpAddr, (u32, Instant)>, // address : drops, start of the strike window
+    bans: HashMap<IpAddr, Instant>,           // address : banned until
+    last_prune: Instant,
+    stats: Arc<Stats>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &Config, stats: Arc<Stats>) -> Self {
+        RateLimiter {
+            enabled: config.rate_limit,
+            message_rate: config.rate_limit_messages as f64,
+            // one per tick, with some slack for acks arriving in bunches
+            ack_rate: config.tick_rate as f64 * 2.0,
+            ban_after: config.rate_limit_ban_after,
+            ban_duration: config.rate_limit_ban,
+            by_address: HashMap::new(),
+            by_client: HashMap::new(),
+            strikes: HashMap::new(),
+            bans: HashMap::new(),
+            last_prune: Instant::now(),
+            stats,
+        }
+    }
+
+    // per client, the address gets ADDRESS_MULTIPLIER times as much
+    fn budget(&self, prefix: Option<Prefixes>) -> Budget {
+        match prefix {
+            // positions are sent every frame, allow a second worth of burst
+            Some(Prefixes::Message) => Budget {
+                rate: self.message_rate,
+                burst: self.message_rate,
+            },
+            Some(Prefixes::PositionAck) => Budget {
+                rate: self.ack_rate,
+                burst: self.ack_rate,
+            },
+            Some(Prefixes::Ping) => Budget { rate: 5.0, burst: 10.0 },
+            // the cookie exchange and roaming take a couple of packets each
+            Some(Prefixes::Hello | Prefixes::Roam) => Budget { rate: 1.0, burst: 5.0 },
+            Some(
+                Prefixes::Disconnect | Prefixes::OutsideLevel | Prefixes::VipActions | Prefixes::PlayerIcons,
+            ) => Budget { rate: 10.0, burst: 20.0 },
+            // server-to-client packets and garbage
+            _ => Budget { rate: 1.0, burst: 2.0 },
+        }
+    }
+
+    /// Returns whether the packet should be handled.
+    pub fn allow(&mut self, address: SocketAddr, data: &[u8]) -> bool {
+        if !self.enabled {
+            return true;
+        }
+
+        let now = Instant::now();
+        if now.duration_since(self.last_prune) >= PRUNE_INTERVAL {
+            self.prune(now);
+        }
+
+        let ip = address.ip();
+        if let Some(until) = self.bans.get(&ip) {
+            if now < *until {
+                Stats::bump(&self.stats.dropped_banned);
+                return false;
+            }
+            self.bans.remove(&ip);
+        }
+
+        let prefix = protocol::peek_prefix(data).ok();
+        let budget = self.budget(prefix);
+
+        let address_budget = Budget {
+            rate: budget.rate * ADDRESS_MULTIPLIER,
+            burst: budget.burst * ADDRESS_MULTIPLIER,
+        };
+        let address_bucket = self
+            .by_address
+            .entry((ip, prefix))
+            .or_insert_with(|| TokenBucket::new(address_budget));
+        address_bucket.refill(address_budget, now);
+
+        let client_bucket = match (prefix, ClientHeader::peek(data)) {
+            (Some(prefix), Ok(header)) => {
+                let bucket = self
+                    .by_client
+                    .entry((ip, header.client_id, prefix))
+                    .or_insert_with(|| TokenBucket::new(budget));
+                bucket.refill(budget, now);
+                Some(bucket)
+            }
+            _ => None,
+        };
+
+        // nothing is taken unless both have a token left, so a client over its own limit
+        // doesn't use up the budget of everyone else behind its address
+        let allowed = address_bucket.has_token() && client_bucket.as_ref().is_none_or(|bucket| bucket.has_token());
+        if allowed {
+            address_bucket.tokens -= 1.0;
+            if let Some(bucket) = client_bucket {
+                bucket.tokens -= 1.0;
+            }
+        } else {
+            self.strike(ip, prefix, now);
+        }
+
+        allowed
+    }
+
+    fn strike(&mut self, ip: IpAddr, prefix: Option<Prefixes>, now: Instant) {
+        Stats::bump(&self.stats.rate_limited);
+
+        let strikes = self.strikes.entry(ip).or_insert((0, now));
+        if now.duration_since(strikes.1) > STRIKE_WINDOW {
+            *strikes = (0, now);
+        }
+        strikes.0 += 1;
+
+        if strikes.0 == 1 {
+            warn!("rate limiting {ip} ({prefix:?} packets)");
+        } else {
+            debug!("rate limited {prefix:?} packet from {ip}, {} drops", strikes.0);
+        }
+
+        if !self.ban_duration.is_zero() && strikes.0 >= self.ban_after {
+            warn!(
+                "banning {ip} for {}s, {} packets dropped in {}s",
+                self.ban_duration.as_secs(),
+                strikes.0,
+                STRIKE_WINDOW.as_secs()
+            );
+            Stats::bump(&self.stats.bans);
+            self.strikes.remove(&ip);
+            self.bans.insert(ip, now + self.ban_duration);
+        }
+    }
+
+    // a bucket that had time to refill completely is the same as no bucket at all
+    fn prune(&mut self, now: Instant) {
+        self.last_prune = now;
+        self.by_address.retain(|_, bucket| now.duration_since(bucket.last) < PRUNE_INTERVAL);
+        self.by_client.retain(|_, bucket| now.duration_since(bucket.last) < PRUNE_INTERVAL);
+        self.strikes.retain(|_, strikes| now.duration_since(strikes.1) <= STRIKE_WINDOW);
+        self.bans.retain(|_, until| now < *until);
+    }
+}
+
+/// The `RateLimiter` shared by all receive workers. The kernel picks a worker by address and port, so packets from
+/// one IP can end up at any of them, and each IP's buckets, strikes and bans are kept together in one shard.
+pub struct SharedRateLimiter {
+    enabled: bool,
+    shards: Vec<Mutex<RateLimiter>>,
+    hasher: RandomState,
+}
+
+impl SharedRateLimiter {
+    pub fn new(config: &Config, stats: Arc<Stats>) -> Self {
+        SharedRateLimiter {
+            enabled: config.rate_limit,
+            shards: (0..LIMITER_SHARDS)
+                .map(|_| Mutex::new(RateLimiter::new(config, stats.clone())))
+                .collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    /// Returns whether the packet should be handled.
+    pub fn allow(&self, address: SocketAddr, data: &[u8]) -> bool {
+        if !self.enabled {
+            return true;
+        }
+
+        let shard = self.hasher.hash_one(address.ip()) as usize % LIMITER_SHARDS;
+        self.shards[shard].lock().unwrap().allow(address, data)
+    }
+}
+
+/// Token buckets per /24 for IPv4 and per /48 for IPv6. Spoofed packets can come from any address,
+/// so a budget per address doesn't hold them back, but one for the whole subnet around it does.
+pub struct SubnetLimiter {
+    enabled: bool,
+    budget: Budget,
+    buckets: HashMap<IpAddr, TokenBucket>,
+    last_prune: Instant,
+}
+
+impl SubnetLimiter {
+    pub fn new(config: &Config) -> Self {
+        let rate = config.unverified_hellos as f64;
+        SubnetLimiter {
+            enabled: config.rate_limit,
+            budget: Budget { rate, burst: rate * 5.0 },
+            buckets: HashMap::new(),
+            last_prune: Instant::now(),
+        }
+    }
+
+    pub fn allow(&mut self, ip: IpAddr) -> bool {
+        if !self.enabled {
+            return true;
+        }
+
+        let now = Instant::now();
+        if now.duration_since(self.last_prune) >= PRUNE_INTERVAL {
+            self.last_prune = now;
+            self.buckets.retain(|_, bucket| now.duration_since(bucket.last) < PRUNE_INTERVAL);
+        }
+
+        let budget = self.budget;
+        self.buckets
+            .entry(subnet(ip))
+            .or_insert_with(|| TokenBucket::new(budget))
+            .take(budget, now)
+    }
+}
+
+fn subnet(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            IpAddr::from([a, b, c, 0])
+        }
+        IpAddr::V6(ip) => {
+            let mut octets = ip.octets();
+            octets[6..].fill(0);
+            IpAddr::from(octets)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{Message, PlayerPosition};
+
+    fn limiter(messages_per_second: u32) -> RateLimiter {
+        let config = Config {
+            rate_limit: true,
+            rate_limit_messages: messages_per_second,
+            ..Config::from_env()
+        };
+        RateLimiter::new(&config, Arc::new(Stats::default()))
+    }
+
+    fn message(client_id: i32) -> Vec<u8> {
+        protocol::encode(&Message {
+            header: ClientHeader {
+                client_id,
+                user_key: 1,
+            },
+            level_id: 1,
+            room: 0,
+            position: PlayerPosition::default(),
+            sequence: None,
+        })
+    }
+
+    fn address(last: u8) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, last], 1234))
+    }
+
+    #[test]
+    fn clients_get_their_own_budget() {
+        let mut limiter = limiter(2);
+
+        assert!(limiter.allow(address(1), &message(1)));
+        assert!(limiter.allow(address(1), &message(1)));
+        assert!(!limiter.allow(address(1), &message(1)));
+
+        // same address, another client
+        assert!(limiter.allow(address(1), &message(2)));
+    }
+
+    #[test]
+    fn dropped_packets_dont_use_up_the_address() {
+        let mut limiter = limiter(1);
+
+        for _ in 0..100 {
+            limiter.allow(address(1), &message(1));
+        }
+
+        // the address gets 8 clients' worth, and only one of them was used
+        for client_id in 2..=8 {
+            assert!(limiter.allow(address(1), &message(client_id)));
+        }
+        assert!(!limiter.allow(address(1), &message(9)));
+    }
+
+    #[test]
+    fn addresses_get_their_own_budget() {
+        let mut limiter = limiter(1);
+
+        assert!(limiter.allow(address(1), &message(1)));
+        assert!(!limiter.allow(address(1), &message(1)));
+        assert!(limiter.allow(address(2), &message(1)));
+    }
+
+    #[test]
+    fn ports_of_an_address_share_its_budget() {
+        let config = Config {
+            rate_limit: true,
+            rate_limit_messages: 1,
+            ..Config::from_env()
+        };
+        let limiter = SharedRateLimiter::new(&config, Arc::new(Stats::default()));
+
+        assert!(limiter.allow(SocketAddr::from(([10, 0, 0, 1], 1000)), &message(1)));
+        assert!(!limiter.allow(SocketAddr::from(([10, 0, 0, 1], 2000)), &message(1)));
+    }
+
+    #[test]
+    fn garbage_is_limited_per_address() {
+        let mut limiter = limiter(1);
+
+        // no client ID to go by, so it's all charged to the address
+        for _ in 0..16 {
+            assert!(limiter.allow(address(1), &[0x7f]));
+        }
+        assert!(!limiter.allow(address(1), &[0x7f]));
+    }
+
+    #[test]
+    fn subnets_share_a_budget() {
+        let config = Config {
+            rate_limit: true,
+            unverified_hellos: 1,
+            ..Config::from_env()
+        };
+        let mut limiter = SubnetLimiter::new(&config);
+
+        for last in 1..=5 {
+            assert!(limiter.allow(address(last).ip()));
+        }
+        assert!(!limiter.allow(address(6).ip()));
+        assert!(limiter.allow(IpAddr::from([10, 0, 1, 1])));
+    }
+}
@@ -0,0 +1,61 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+    time::Instant,
+};
+
+use arc_swap::ArcSwap;
+
+use crate::state::LevelKey;
+
+pub struct LobbySummary {
+    pub name: String,
+    pub owner: i32,
+}
+
+/// Read-only copy of what the HTTP endpoints show, published by the game loop.
+pub struct Snapshot {
+    pub taken: Instant,
+    pub online: usize,
+    pub levels: BTreeMap<LevelKey, usize>, // level and room : players in it
+    pub lobbies: HashMap<u16, LobbySummary>,
+}
+
+impl Snapshot {
+    pub fn empty() -> Self {
+        Snapshot {
+            taken: Instant::now(),
+            online: 0,
+            levels: BTreeMap::new(),
+            lobbies: HashMap::new(),
+        }
+    }
+}
+
+/// The latest snapshot. Readers never lock anything, and the game loop swaps in a new one
+/// whenever the current one gets older than the configured max age.
+pub struct Published {
+    current: ArcSwap<Snapshot>,
+}
+
+impl Published {
+    pub fn new() -> Self {
+        Published {
+            current: ArcSwap::from_pointee(Snapshot::empty()),
+        }
+    }
+
+    pub fn load(&self) -> Arc<Snapshot> {
+        self.current.load_full()
+    }
+
+    pub fn publish(&self, snapshot: Snapshot) {
+        self.current.store(Arc::new(snapshot));
+    }
+}
+
+impl Default for Published {
+    fn default() -> Self {
+        Self::new()
+    }
+}
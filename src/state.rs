@@ -1,92 +1,1075 @@
-use std::{collections::HashMap, sync::Arc, net::SocketAddr, time::{SystemTime, Duration}};
+use std::{collections::{BTreeMap, HashMap, HashSet}, sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex}, net::{IpAddr, SocketAddr}, time::{SystemTime, Duration, Instant}};
 
 use anyhow::anyhow;
-use bytebuffer::{ByteBuffer, Endian};
-use log::debug;
-use tokio::{net::UdpSocket, sync::Mutex};
-use crate::gdm_server::{PlayerPosition, Prefixes};
+use log::{debug, warn};
+use rand::Rng;
+use tokio::{net::UdpSocket, sync::Mutex as AsyncMutex};
+use crate::protocol::{self, ClientHeader, FEATURE_BATCHED_POSITIONS, FEATURE_CLIENT_RAINBOW, FEATURE_DELTA_POSITIONS, FEATURE_SESSION_KEYS, LobbyResult, PlayerDisconnect, PlayerMessage, PlayerPosition, ServerData, ServerDataKind};
+use crate::colors::ColorProfiles;
+use crate::config::Config;
+use crate::delta::{DeltaTracker, SentPositions};
+use crate::cookie::CookieJar;
+use crate::icon_cache::IconCache;
+use crate::icons::IconSheets;
+use crate::interest::InterestFilter;
+use crate::outbound::Outbound;
+use crate::rate_limit::{SharedRateLimiter, SubnetLimiter};
+use crate::snapshot::{LobbySummary, Published, Snapshot};
+use crate::stats::Stats;
+use crate::upstream::UpstreamPool;
+use crate::vip::VipRegistry;
 
+// how long a client has to answer a roam challenge
+const ROAM_CHALLENGE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// levels are spread over this many separately locked maps
+const LEVEL_SHARDS: usize = 16;
+
+// level ID and room, room 0 is the public one
+pub type LevelKey = (i32, i16);
+
+pub type Players = HashMap<i32, PlayerPosition>;
+
+// private lobby, its players use the lobby code as the room in position packets
+pub struct Lobby {
+    pub name: String,
+    pub owner: i32,
+    pub members: HashSet<i32>,
+}
+
+// Connecting -> Authenticated <-> InLevel, and from any of them to TimedOut or Closed.
+// a timed out session goes back to where it was if the client shows up again in time, otherwise it's closed and removed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionState {
+    Connecting,        // got its AckHello, hasn't used the key yet
+    Authenticated,     // not on a level
+    InLevel(LevelKey), // in `levels` under this key
+    // stopped pinging, stays in `levels` frozen in place while it can still be resumed
+    TimedOut { level: Option<LevelKey> },
+    Closed, // disconnected, being cleaned up
+}
+
+pub struct Session {
+    pub address: SocketAddr,
+    pub key: u32,
+    pub last_ping: SystemTime,
+    pub last_position: SystemTime, // last Message with a level, for the idle timeout
+    pub features: u32,             // FEATURE_* flags from its hello
+    pub state: SessionState,
+    pub refused_room: Option<i16>, // last room it was kept out of, so it's only told once
+}
+
+impl Session {
+    pub fn level(&self) -> Option<LevelKey> {
+        match self.state {
+            SessionState::InLevel(key) | SessionState::TimedOut { level: Some(key) } => Some(key),
+            _ => None,
+        }
+    }
+
+    // timed out sessions don't get anything sent to them and can't move until they're resumed
+    pub fn is_open(&self) -> bool {
+        !matches!(self.state, SessionState::TimedOut { .. } | SessionState::Closed)
+    }
+
+    /// Called for every packet that passed the key check. Returns the state the session left, if it changed.
+    pub fn heard_from(&mut self) -> Option<SessionState> {
+        let previous = self.state;
+        match previous {
+            SessionState::Connecting => {
+                self.state = SessionState::Authenticated;
+            }
+            SessionState::TimedOut { level } => {
+                self.state = match level {
+                    Some(level) => SessionState::InLevel(level),
+                    None => SessionState::Authenticated,
+                };
+                // it missed the pings, but isn't idle
+                self.last_ping = SystemTime::now();
+                self.last_position = SystemTime::now();
+            }
+            _ => return None,
+        }
+        Some(previous)
+    }
+
+    /// Times the session out if it stopped pinging, and says what else has to be done with it.
+    pub fn check_timeouts(&mut self, config: &Config) -> Timeout {
+        if is_expired(self.last_ping, config.ping_timeout + config.resume_grace) {
+            Timeout::Expired
+        } else if !self.is_open() {
+            Timeout::None
+        } else if is_expired(self.last_ping, config.ping_timeout) {
+            // peers keep seeing the player where they were, so a quick reconnect goes unnoticed
+            self.state = SessionState::TimedOut { level: self.level() };
+            Timeout::TimedOut
+        } else if self.level().is_some()
+            && !config.idle_timeout.is_zero()
+            && is_expired(self.last_position, config.idle_timeout)
+        {
+            // still pinging, but hasn't moved in a while, most likely left without saying so
+            Timeout::Idle
+        } else {
+            Timeout::None
+        }
+    }
+}
+
+/// What the reaper has to do with a session, see `Session::check_timeouts`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timeout {
+    None,
+    TimedOut, // just stopped pinging, can still be resumed
+    Expired,  // wasn't resumed in time, has to be closed
+    Idle,     // still pinging but not moving, has to be taken off its level
+}
+
+/// What became of a hello, see `Sessions::hello`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HelloOutcome {
+    Accepted { resumed: bool },
+    InUse,     // someone else has the ID
+    Roam(u32), // the right key from a new address, the nonce of the challenge sent there
+}
+
+#[derive(Default)]
+pub struct Sessions {
+    pub clients: HashMap<i32, Session>,
+    pub pending_roams: HashMap<i32, (SocketAddr, u32, SystemTime)>, // client_id : new address, nonce, timestamp of the challenge
+    pub bad_key_offenders: HashMap<IpAddr, (u32, SystemTime)>, // address : rejected packets, timestamp of the last one
+}
+
+impl Sessions {
+    pub fn check_key(&self, header: &ClientHeader) -> Result<(), &'static str> {
+        match self.clients.get(&header.client_id) {
+            None => Err("not connected"),
+            Some(session) if session.key != header.user_key => Err("wrong key"),
+            Some(session) if session.state == SessionState::Closed => Err("session closed"),
+            Some(_) => Ok(()),
+        }
+    }
+
+    /// Whether a hello should close the client's timed out session and start over. It can only be resumed
+    /// with its key, and from its own address unless the client is able to answer a roam challenge.
+    pub fn is_stale(&self, client_id: i32, address: SocketAddr, user_key: u32) -> bool {
+        self.clients.get(&client_id).is_some_and(|session| {
+            let roams = session.features & FEATURE_SESSION_KEYS != 0;
+            !session.is_open() && (session.key != user_key || (session.address != address && !roams))
+        })
+    }
+
+    /// Starts a session for a hello, or picks up the existing one if the hello has its key.
+    /// A hello from another address doesn't move the session, it only gets a roam challenge sent there.
+    pub fn hello(
+        &mut self,
+        client_id: i32,
+        address: SocketAddr,
+        user_key: u32,
+        session_key: u32,
+        features: u32,
+    ) -> HelloOutcome {
+        match self.clients.get(&client_id) {
+            // a session can only be taken over by whoever holds its key
+            Some(session) if session.key != user_key => HelloOutcome::InUse,
+            Some(session) if session.address != address => HelloOutcome::Roam(self.roam_challenge(client_id, address)),
+            previous => {
+                let resumed = previous.is_some_and(|session| !session.is_open());
+
+                // saying hello again doesn't take the player off their level
+                let state = match previous.and_then(|session| session.level()) {
+                    Some(level) => SessionState::InLevel(level),
+                    None => SessionState::Connecting,
+                };
+                self.clients.insert(
+                    client_id,
+                    Session {
+                        address,
+                        key: session_key,
+                        last_ping: SystemTime::now(),
+                        last_position: SystemTime::now(),
+                        features,
+                        state,
+                        refused_room: None,
+                    },
+                );
+                HelloOutcome::Accepted { resumed }
+            }
+        }
+    }
+
+    pub fn address_of(&self, client_id: &i32) -> Option<SocketAddr> {
+        self.clients.get(client_id).map(|session| session.address)
+    }
+
+    // finds the session of a client that didn't say who it is
+    pub fn client_at(&self, address: SocketAddr, key: u32) -> Option<i32> {
+        self.clients
+            .iter()
+            .find(|(_, session)| session.address == address && session.key == key)
+            .map(|(client_id, _)| *client_id)
+    }
+
+    pub fn has_feature(&self, client_id: &i32, feature: u32) -> bool {
+        self.clients
+            .get(client_id)
+            .is_some_and(|session| session.features & feature != 0)
+    }
+
+    pub fn roam_challenge(&mut self, client_id: i32, address: SocketAddr) -> u32 {
+        // keep the nonce if the client is already being challenged at that address, so a late answer still counts
+        if let Some(pending) = self.pending_roams.get(&client_id) {
+            if pending.0 == address && !is_expired(pending.2, ROAM_CHALLENGE_TIMEOUT) {
+                return pending.1;
+            }
+        }
+
+        let nonce = rand::thread_rng().gen();
+        self.pending_roams
+            .insert(client_id, (address, nonce, SystemTime::now()));
+        nonce
+    }
+
+    pub fn complete_roam(&mut self, client_id: i32, address: SocketAddr, nonce: u32) -> bool {
+        let valid = self.pending_roams.get(&client_id).is_some_and(|pending| {
+            pending.0 == address && pending.1 == nonce && !is_expired(pending.2, ROAM_CHALLENGE_TIMEOUT)
+        });
+
+        if !valid {
+            return false;
+        }
+
+        self.pending_roams.remove(&client_id);
+        if let Some(session) = self.clients.get_mut(&client_id) {
+            session.address = address;
+        }
+        true
+    }
+
+    // returns how many times the address has been rejected so far
+    pub fn record_bad_key(&mut self, address: IpAddr) -> u32 {
+        let offender = self
+            .bad_key_offenders
+            .entry(address)
+            .or_insert((0, SystemTime::now()));
+        offender.0 += 1;
+        offender.1 = SystemTime::now();
+        offender.0
+    }
+}
+
+/// Players in every level and room. Levels are sharded, so players on different levels don't wait for each other.
+pub struct Levels {
+    shards: Vec<Mutex<HashMap<LevelKey, Players>>>,
+}
+
+impl Levels {
+    fn new() -> Self {
+        Levels {
+            shards: (0..LEVEL_SHARDS).map(|_| Mutex::new(HashMap::new())).collect(),
+        }
+    }
+
+    fn shard(&self, key: &LevelKey) -> &Mutex<HashMap<LevelKey, Players>> {
+        let hash = (key.0 as u32).wrapping_mul(31).wrapping_add(key.1 as u32);
+        &self.shards[hash as usize % LEVEL_SHARDS]
+    }
+
+    // returns whether the player just joined
+    fn insert(&self, key: LevelKey, client_id: i32, position: PlayerPosition) -> bool {
+        let mut shard = self.shard(&key).lock().unwrap();
+        shard.entry(key).or_default().insert(client_id, position).is_none()
+    }
+
+    // returns everyone that's still there
+    fn remove(&self, key: &LevelKey, client_id: i32) -> Vec<i32> {
+        let mut shard = self.shard(key).lock().unwrap();
+        let Some(players) = shard.get_mut(key) else {
+            return vec![];
+        };
+
+        players.remove(&client_id);
+        let remaining = players.keys().copied().collect();
+
+        // remove levels with 0 players
+        if players.is_empty() {
+            shard.remove(key);
+        }
+
+        remaining
+    }
+
+    /// Calls `f` for every level. Each shard is locked on its own while it's being walked.
+    pub fn for_each(&self, mut f: impl FnMut(&LevelKey, &Players)) {
+        for shard in self.shards.iter() {
+            for (key, players) in shard.lock().unwrap().iter() {
+                f(key, players);
+            }
+        }
+    }
+}
+
+/// Server state, shared between the UDP server and the HTTP routes. Every part has its own lock,
+/// and the synchronous ones can't be held across an `.await`, so nothing is ever locked during socket I/O.
+///
+/// When more than one lock is needed, they're taken in this order: `sessions`, then a `levels` shard,
+/// and `sessions`, then `deltas`, then `interest`, then `sent_positions`. `vips`, the only async one, is never
+/// locked while holding a synchronous one.
 pub struct State {
-    pub levels: HashMap<i32, HashMap<i32, PlayerPosition>>,
-    pub server_socket: Arc<UdpSocket>,
-    pub connected_clients: HashMap<i32, (SocketAddr, u32, SystemTime)>, // client_id : address, user key, timestamp of last ping
+    pub levels: Levels,
+    pub outbound: Outbound,
+    pub sessions: Mutex<Sessions>,
+    pub lobbies: Mutex<HashMap<u16, Lobby>>,
+    pub deltas: Mutex<DeltaTracker>,
+    pub sent_positions: Mutex<SentPositions>,
+    pub interest: Mutex<InterestFilter>,
+    pub tick: AtomicU64,
+    pub motd: Mutex<Option<String>>,
+    pub level_notices: Mutex<HashMap<i32, String>>, // level_id : notice shown when joining the level
+    pub config: Config,
+    pub cookies: CookieJar,
+    pub rate_limiter: SharedRateLimiter,
+    pub unverified_hellos: Mutex<SubnetLimiter>, // hellos without a cookie, per subnet
+    pub vips: AsyncMutex<VipRegistry>, // async lock, writes to disk while locked
+    pub colors: ColorProfiles,         // read without locking, see `ColorProfiles`
+    pub icon_sheets: Arc<IconSheets>,
+    pub icon_cache: Arc<IconCache>,
+    pub icon_upstreams: Arc<UpstreamPool>,
+    pub stats: Arc<Stats>,
+    pub snapshot: Published,
 }
 
 impl State {
-    pub fn new(server_socket: Arc<UdpSocket>) -> Self {
+    pub fn new(
+        server_socket: Arc<UdpSocket>,
+        config: Config,
+        vips: VipRegistry,
+        colors: ColorProfiles,
+        icon_sheets: IconSheets,
+        icon_cache: IconCache,
+        icon_upstreams: UpstreamPool,
+    ) -> Self {
+        let stats = Arc::new(Stats::default());
         State {
-            levels: HashMap::new(),
-            server_socket,
-            connected_clients: HashMap::new(),
+            levels: Levels::new(),
+            outbound: Outbound::new(server_socket, &config, stats.clone()),
+            sessions: Mutex::new(Sessions::default()),
+            lobbies: Mutex::new(HashMap::new()),
+            deltas: Mutex::new(DeltaTracker::default()),
+            sent_positions: Mutex::new(SentPositions::default()),
+            interest: Mutex::new(InterestFilter::new(&config)),
+            tick: AtomicU64::new(0),
+            motd: Mutex::new(config.motd.clone()),
+            level_notices: Mutex::new(HashMap::new()),
+            rate_limiter: SharedRateLimiter::new(&config, stats.clone()),
+            unverified_hellos: Mutex::new(SubnetLimiter::new(&config)),
+            config,
+            cookies: CookieJar::new(),
+            vips: AsyncMutex::new(vips),
+            colors,
+            icon_sheets: Arc::new(icon_sheets),
+            icon_cache: Arc::new(icon_cache),
+            icon_upstreams: Arc::new(icon_upstreams),
+            stats,
+            snapshot: Published::new(),
+        }
+    }
+
+    pub fn new_session_key() -> u32 {
+        rand::thread_rng().gen()
+    }
+
+    /// Takes the player off their level, returns who's still there to notify.
+    pub fn left_level(&self, user: &i32) -> Vec<i32> {
+        // the session table stays locked while the level is changed, so the reaper can't slip in between
+        // and leave a player on a level without a session
+        let mut sessions = self.sessions.lock().unwrap();
+        let Some(session) = sessions.clients.get_mut(user) else {
+            return vec![];
+        };
+
+        let Some(key) = session.level() else {
+            return vec![];
+        };
+        session.state = SessionState::Authenticated;
+
+        debug!("{user} left the level {} (room {})", key.0, key.1);
+        self.levels.remove(&key, *user)
+    }
+
+    /// Updates the player's position, moving them over if they switched levels or rooms without leaving first.
+    /// Returns the players of the previous level to notify, and whether the player just joined this one.
+    pub fn enter_level(&self, user: i32, key: LevelKey, position: PlayerPosition) -> (Vec<i32>, bool) {
+        let mut sessions = self.sessions.lock().unwrap();
+        let Some(session) = sessions.clients.get_mut(&user).filter(|session| session.is_open()) else {
+            // disconnected in the meantime
+            return (vec![], false);
+        };
+
+        let previous = session.level();
+        session.state = SessionState::InLevel(key);
+        session.last_position = SystemTime::now();
+        session.refused_room = None;
+
+        let left = match previous {
+            Some(previous) if previous != key => self.levels.remove(&previous, user),
+            _ => vec![],
+        };
+
+        let joined = self.levels.insert(key, user, position);
+        (left, joined)
+    }
+
+    /// Ends a session for good. Every way out goes through here, so the player is always taken off their level
+    /// and everyone still there is told about it.
+    pub async fn close_session(&self, client_id: i32) {
+        let remaining = self.end_session(&mut self.sessions.lock().unwrap(), client_id);
+        if let Some(remaining) = remaining {
+            self.clean_up_session(client_id, &remaining).await;
         }
     }
 
-    pub fn left_level(&mut self, user: &i32) -> Vec<i32> {
-        // returns users to notify about exit
+    // takes the session out of the table and the player off their level, returns who's left there
+    fn end_session(&self, sessions: &mut Sessions, client_id: i32) -> Option<Vec<i32>> {
+        let session = sessions.clients.get_mut(&client_id)?;
+
+        let level = session.level();
+        debug!("closing the session of {client_id} ({:?})", session.state);
+        session.state = SessionState::Closed;
+        let remaining = level.map(|key| self.levels.remove(&key, client_id)).unwrap_or_default();
+
+        sessions.clients.remove(&client_id);
+        sessions.pending_roams.remove(&client_id);
+        Some(remaining)
+    }
+
+    // the rest of closing a session, once the session table is unlocked
+    async fn clean_up_session(&self, client_id: i32, remaining: &[i32]) {
+        self.leave_lobby(&client_id).await;
+        self.deltas.lock().unwrap().forget(client_id);
+        self.interest.lock().unwrap().retain(|recipient| recipient != client_id);
+        self.notify_clients(remaining, &client_id).await;
+    }
+
+    pub async fn create_lobby(&self, code: u16, owner: i32, name: String) -> LobbyResult {
+        if !self.vips.lock().await.is_vip(owner) {
+            return LobbyResult::NotVip;
+        }
+
+        if code == 0 || code > i16::MAX as u16 {
+            return LobbyResult::InvalidCode;
+        }
 
-        let mut users_in_level = vec![];
-        // remove from existing levels, if applicable
-        for (level_id, level_players) in self.levels.iter_mut() {
-            if level_players.contains_key(user) {
-                level_players.remove(user);
-                users_in_level.extend(level_players.keys().copied().collect::<Vec<i32>>());
-                debug!("{user} left the level {level_id}");
-                break;
+        {
+            let mut lobbies = self.lobbies.lock().unwrap();
+            if lobbies.contains_key(&code) {
+                return LobbyResult::CodeTaken;
             }
+
+            leave_lobby(&mut lobbies, &owner);
+            debug!("{owner} created the lobby {code} ({name:?})");
+            lobbies.insert(
+                code,
+                Lobby {
+                    name,
+                    owner,
+                    members: HashSet::from([owner]),
+                },
+            );
         }
 
-        // remove levels with 0 players
-        self.levels.retain(|_, v| !v.is_empty());
+        self.leave_closed_room(&owner).await;
+        LobbyResult::Ok
+    }
+
+    pub async fn join_lobby(&self, code: u16, client: i32) -> LobbyResult {
+        {
+            let mut lobbies = self.lobbies.lock().unwrap();
+            if !lobbies.contains_key(&code) {
+                return LobbyResult::NotFound;
+            }
 
-        users_in_level
+            leave_lobby(&mut lobbies, &client);
+            if let Some(lobby) = lobbies.get_mut(&code) {
+                lobby.members.insert(client);
+                debug!("{client} joined the lobby {code}");
+            }
+        }
+
+        self.leave_closed_room(&client).await;
+        LobbyResult::Ok
+    }
+
+    /// Takes the client out of their lobby, and off the lobby's room if they're in it.
+    pub async fn leave_lobby(&self, client: &i32) {
+        leave_lobby(&mut self.lobbies.lock().unwrap(), client);
+        self.leave_closed_room(client).await;
     }
 
+    // a player whose room belongs to a lobby they're no longer in is taken off the level
+    async fn leave_closed_room(&self, client: &i32) {
+        let room = self
+            .sessions
+            .lock()
+            .unwrap()
+            .clients
+            .get(client)
+            .and_then(|session| session.level())
+            .map(|(_, room)| room);
+
+        if room.is_some_and(|room| !self.can_enter_room(client, room)) {
+            let clients = self.left_level(client);
+            self.notify_clients(&clients, client).await;
+        }
+    }
+
+    pub fn can_enter_room(&self, client: &i32, room: i16) -> bool {
+        // the public room is open to everyone, rooms that belong to a lobby only to its members.
+        // any other room is open too, players can just agree on a number
+        if room <= 0 {
+            return true;
+        }
+
+        self.lobbies
+            .lock()
+            .unwrap()
+            .get(&(room as u16))
+            .is_none_or(|lobby| lobby.members.contains(client))
+    }
+
+    /// Remembers that the client was kept out of the room. Returns whether it's the first time in a row,
+    /// so the client is told once instead of for every position it sends.
+    pub fn refuse_room(&self, client: &i32, room: i16) -> bool {
+        let mut sessions = self.sessions.lock().unwrap();
+        let Some(session) = sessions.clients.get_mut(client) else {
+            return false;
+        };
+
+        let first = session.refused_room != Some(room);
+        session.refused_room = Some(room);
+        first
+    }
+
+    // returns how many of them were notified
     pub async fn notify_clients(
         &self,
-        clients: &Vec<i32>,
+        clients: &[i32],
         client_left: &i32,
-    ) -> anyhow::Result<()> {
+    ) -> usize {
         debug!(
             "notifying {} clients that {client_left} left",
             clients.len()
         );
+        self.sent_positions.lock().unwrap().forget(*client_left);
+        let data = protocol::encode(&PlayerDisconnect {
+            player_id: *client_left,
+        });
+        let mut sent = 0;
         for client_id in clients.iter() {
-            let mut buf = ByteBuffer::new();
-            buf.set_endian(Endian::LittleEndian);
-            buf.write_i8(Prefixes::PlayerDisconnect.to_number());
-            buf.write_i32(*client_left);
-            self.send_to(client_id, buf.as_bytes()).await?;
+            match self.send_to(client_id, &data).await {
+                Ok(_) => sent += 1,
+                Err(e) => debug!("failed to notify {client_id} that {client_left} left: {e}"),
+            }
         }
+        sent
+    }
+
+    pub async fn send_server_data(
+        &self,
+        client_id: &i32,
+        kind: ServerDataKind,
+        message: String,
+    ) -> anyhow::Result<()> {
+        let packet = ServerData { kind, message };
+        self.send_to(client_id, &protocol::encode(&packet)).await?;
         Ok(())
     }
 
-    pub async fn send_to(&self, client_id: &i32, data: &[u8]) -> anyhow::Result<usize> {
-        let client = self.connected_clients.get(client_id);
-        if client.is_none() {
-            return Err(anyhow!("Client not found by id {client_id}"));
+    pub async fn send_motd(&self, client_id: &i32) {
+        let motd = self.motd.lock().unwrap().clone();
+        if let Some(motd) = motd {
+            if let Err(e) = self.send_server_data(client_id, ServerDataKind::Motd, motd).await {
+                debug!("failed to send the MOTD to {client_id}: {e}");
+            }
         }
+    }
 
-        let client = client.unwrap();
-        Ok(self.server_socket.send_to(data, client.0).await?)
+    // one unreachable client shouldn't stop everyone else from getting the message
+    pub async fn broadcast_server_data(
+        &self,
+        clients: &[i32],
+        kind: ServerDataKind,
+        message: String,
+    ) -> usize {
+        let data = protocol::encode(&ServerData { kind, message });
+        let mut sent = 0;
+        for client_id in clients.iter() {
+            match self.send_to(client_id, &data).await {
+                Ok(_) => sent += 1,
+                Err(e) => warn!("failed to send server data to {client_id}: {e}"),
+            }
+        }
+        sent
     }
 
-    pub async fn remove_dead_clients(&mut self) {
-        let now = SystemTime::now();
-        self.connected_clients.retain(|_, client| {
-            let elapsed = now
-                .duration_since(client.2)
-                .unwrap_or_else(|_| Duration::from_secs(0));
-            elapsed < Duration::from_secs(60)
+    /// Sends everyone the latest position of every other player in their level and room, called once per tick.
+    /// Returns how many datagrams were queued.
+    pub async fn send_positions(&self) -> usize {
+        let tick = self.tick.fetch_add(1, Ordering::Relaxed).wrapping_add(1);
+
+        // copied out, so the levels are only locked for as long as that takes. levels with one player are kept,
+        // delta recipients still have to be told when the others left
+        let mut levels: Vec<Players> = vec![];
+        self.levels.for_each(|_, players| {
+            if !players.is_empty() {
+                levels.push(players.clone());
+            }
+        });
+
+        if levels.is_empty() {
+            return 0;
+        }
+
+        let mut rainbow = HashMap::new();
+        if self.config.server_rainbow {
+            let colors = self.colors.current();
+            for player_id in levels.iter().flat_map(|players| players.keys()) {
+                let color = colors
+                    .get(player_id)
+                    .and_then(|profile| profile.animated_colors(*player_id));
+
+                if let Some(color) = color {
+                    rainbow.insert(*player_id, color);
+                }
+            }
+        }
+
+        let mut outgoing: Vec<(SocketAddr, Vec<u8>)> = vec![];
+        {
+            let sessions = self.sessions.lock().unwrap();
+            let mut deltas = self.deltas.lock().unwrap();
+            let mut interest = self.interest.lock().unwrap();
+            let mut sent_positions = self.sent_positions.lock().unwrap();
+            // players that didn't move are still sent about once a second
+            let refresh = self.config.tick_rate as u64;
+
+            for players in levels.iter() {
+                let positions: Vec<(i32, PlayerPosition)> = players
+                    .iter()
+                    .map(|(player_id, pos)| (*player_id, pos.clone()))
+                    .collect();
+                let messages = encode_positions(&positions);
+
+                // what clients that can't animate rainbow colors get instead
+                let mut painted = vec![];
+                let mut painted_messages = vec![];
+                if !rainbow.is_empty() {
+                    painted = positions
+                        .iter()
+                        .map(|(player_id, pos)| {
+                            let mut pos = pos.clone();
+                            if let Some((color1, color2)) = rainbow.get(player_id) {
+                                pos.color1 = *color1;
+                                pos.color2 = *color2;
+                            }
+                            (*player_id, pos)
+                        })
+                        .collect();
+                    painted_messages = encode_positions(&painted);
+                }
+
+                for recipient in players.keys() {
+                    let address = sessions
+                        .clients
+                        .get(recipient)
+                        .filter(|session| session.is_open())
+                        .map(|session| session.address);
+                    let Some(address) = address else {
+                        continue;
+                    };
+
+                    let selection = interest.select(*recipient, players, tick);
+
+                    let (positions, messages) =
+                        if !rainbow.is_empty() && !sessions.has_feature(recipient, FEATURE_CLIENT_RAINBOW) {
+                            (&painted, &painted_messages)
+                        } else {
+                            (&positions, &messages)
+                        };
+
+                    // pushed out by closer players, the client can forget about them until they come back
+                    for player_id in selection.hidden.iter() {
+                        let packet = PlayerDisconnect {
+                            player_id: *player_id,
+                        };
+                        outgoing.push((address, protocol::encode(&packet)));
+                        sent_positions.hidden(*recipient, *player_id);
+                    }
+
+                    // full positions only for the players that moved since they were last sent
+                    let others = positions
+                        .iter()
+                        .zip(messages.iter())
+                        .filter(|((player_id, _), _)| selection.fresh.contains(player_id))
+                        .filter(|((player_id, pos), _)| {
+                            sent_positions.needs(*recipient, *player_id, pos, tick, refresh)
+                        })
+                        .map(|(_, (_, data))| data.as_slice());
+
+                    let datagrams = if sessions.has_feature(recipient, FEATURE_DELTA_POSITIONS) {
+                        // players that aren't due this tick stay where the client last saw them
+                        let visible = positions
+                            .iter()
+                            .filter(|(player_id, _)| selection.visible.contains(player_id))
+                            .map(|(player_id, pos)| {
+                                let pos = if selection.fresh.contains(player_id) {
+                                    pos
+                                } else {
+                                    deltas.last_sent(*recipient, *player_id).unwrap_or(pos)
+                                };
+                                (*player_id, pos.clone())
+                            })
+                            .collect();
+                        deltas.encode(*recipient, visible, self.config.batch_mtu)
+                    } else if sessions.has_feature(recipient, FEATURE_BATCHED_POSITIONS) {
+                        protocol::batch_player_messages(others, self.config.batch_mtu)
+                    } else {
+                        // stock clients only understand one player per datagram
+                        others.map(|data| data.to_vec()).collect()
+                    };
+
+                    outgoing.extend(datagrams.into_iter().map(|data| (address, data)));
+                }
+            }
+        }
+
+        let mut sent = 0;
+        for (address, data) in outgoing {
+            match self.outbound.send(address, data) {
+                Ok(_) => sent += 1,
+                Err(e) => debug!("failed to send positions to {address}: {e}"),
+            }
+        }
+        sent
+    }
+
+    /// Publishes a new snapshot for the HTTP endpoints if the current one is older than `snapshot_max_age`.
+    /// Called from the tick, so the game state is only ever read from here and not by every HTTP request.
+    pub fn refresh_snapshot(&self) {
+        if self.snapshot.load().taken.elapsed() < self.config.snapshot_max_age {
+            return;
+        }
+
+        let mut levels = BTreeMap::new();
+        self.levels.for_each(|key, players| {
+            levels.insert(*key, players.len());
+        });
+
+        let lobbies = self
+            .lobbies
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(code, lobby)| {
+                let summary = LobbySummary {
+                    name: lobby.name.clone(),
+                    owner: lobby.owner,
+                };
+                (*code, summary)
+            })
+            .collect();
+
+        self.snapshot.publish(Snapshot {
+            taken: Instant::now(),
+            online: self.online_players(),
+            levels,
+            lobbies,
         });
     }
 
-    pub async fn update_client_time(&mut self, client_id: &i32) {
-        if let Some(client) = self.connected_clients.get_mut(client_id) {
-            client.2 = SystemTime::now();
+    pub fn players_in_level(&self, level_id: i32) -> Vec<i32> {
+        let mut players_in_level = vec![];
+        self.levels.for_each(|(id, _), players| {
+            if *id == level_id {
+                players_in_level.extend(players.keys().copied());
+            }
+        });
+        players_in_level
+    }
+
+    pub fn online_players(&self) -> usize {
+        self.sessions.lock().unwrap().clients.len()
+    }
+
+    pub async fn send_to(&self, client_id: &i32, data: &[u8]) -> anyhow::Result<()> {
+        let address = self.sessions.lock().unwrap().address_of(client_id);
+        let Some(address) = address else {
+            Stats::bump(&self.stats.unknown_recipient);
+            return Err(anyhow!("Client not found by id {client_id}"));
+        };
+
+        self.outbound.send(address, data.to_vec())
+    }
+
+    /// Times out sessions that stopped pinging, closes the ones that weren't resumed in time
+    /// and takes idle players off their level, notifying everyone affected.
+    pub async fn remove_dead_clients(&self) {
+        let now = SystemTime::now();
+        let (closed, idle) = {
+            let mut sessions = self.sessions.lock().unwrap();
+
+            let mut expired = vec![];
+            let mut idle = vec![];
+            for (client_id, session) in sessions.clients.iter_mut() {
+                match session.check_timeouts(&self.config) {
+                    Timeout::Expired => expired.push(*client_id),
+                    Timeout::TimedOut => {
+                        debug!("{client_id} timed out, they can resume for {}s", self.config.resume_grace.as_secs())
+                    }
+                    Timeout::Idle => idle.push(*client_id),
+                    Timeout::None => {}
+                }
+            }
+
+            // closed while still locked, so they can't be resumed halfway through
+            let closed: Vec<(i32, Vec<i32>)> = expired
+                .into_iter()
+                .filter_map(|client_id| Some((client_id, self.end_session(&mut sessions, client_id)?)))
+                .collect();
+
+            sessions
+                .pending_roams
+                .retain(|_, pending| !is_expired(pending.2, ROAM_CHALLENGE_TIMEOUT));
+
+            // offenders are forgiven after a while
+            sessions.bad_key_offenders.retain(|_, offender| {
+                now.duration_since(offender.1)
+                    .unwrap_or_else(|_| Duration::from_secs(0))
+                    < Duration::from_secs(600)
+            });
+
+            (closed, idle)
+        };
+
+        for client_id in idle {
+            debug!("{client_id} is idle, taking them off their level");
+            let clients = self.left_level(&client_id);
+            self.notify_clients(&clients, &client_id).await;
+        }
+
+        for (client_id, remaining) in closed {
+            self.clean_up_session(client_id, &remaining).await;
+        }
+
+        // anything that belonged to sessions that are gone by now
+        let alive: HashSet<i32> = self.sessions.lock().unwrap().clients.keys().copied().collect();
+        self.deltas.lock().unwrap().retain(|client_id| alive.contains(&client_id));
+        self.sent_positions.lock().unwrap().retain(|client_id| alive.contains(&client_id));
+        self.interest.lock().unwrap().retain(|client_id| alive.contains(&client_id));
+
+        let mut lobbies = self.lobbies.lock().unwrap();
+        let dead_members: Vec<i32> = lobbies
+            .values()
+            .flat_map(|lobby| lobby.members.iter().copied())
+            .filter(|member| !alive.contains(member))
+            .collect();
+
+        for member in dead_members.iter() {
+            leave_lobby(&mut lobbies, member);
+        }
+    }
+
+    pub fn update_client_time(&self, client_id: &i32) {
+        if let Some(session) = self.sessions.lock().unwrap().clients.get_mut(client_id) {
+            session.last_ping = SystemTime::now();
         }
     }
 }
 
+// every position is encoded once and sent to everyone else
+fn encode_positions(positions: &[(i32, PlayerPosition)]) -> Vec<(i32, Vec<u8>)> {
+    positions
+        .iter()
+        .map(|(player_id, pos)| {
+            let message = PlayerMessage {
+                player_id: *player_id,
+                position: pos.clone(),
+            };
+            (*player_id, protocol::encode(&message))
+        })
+        .collect()
+}
+
+fn leave_lobby(lobbies: &mut HashMap<u16, Lobby>, client: &i32) {
+    for lobby in lobbies.values_mut() {
+        lobby.members.remove(client);
+    }
+
+    // lobbies expire as soon as the last member leaves
+    lobbies.retain(|code, lobby| {
+        if lobby.members.is_empty() {
+            debug!("lobby {code} expired");
+        }
+        !lobby.members.is_empty()
+    });
+}
+
+fn is_expired(timestamp: SystemTime, timeout: Duration) -> bool {
+    SystemTime::now()
+        .duration_since(timestamp)
+        .unwrap_or_else(|_| Duration::from_secs(0))
+        >= timeout
+}
 
 // Thread Safe State shorthand
-pub type TSState = Arc<Mutex<State>>;
\ No newline at end of file
+pub type TSState = Arc<State>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        Config {
+            ping_timeout: Duration::from_secs(60),
+            resume_grace: Duration::from_secs(30),
+            idle_timeout: Duration::from_secs(600),
+            ..Config::from_env()
+        }
+    }
+
+    fn session(state: SessionState) -> Session {
+        Session {
+            address: SocketAddr::from(([10, 0, 0, 1], 1234)),
+            key: 1,
+            last_ping: SystemTime::now(),
+            last_position: SystemTime::now(),
+            features: 0,
+            state,
+            refused_room: None,
+        }
+    }
+
+    fn ago(secs: u64) -> SystemTime {
+        SystemTime::now() - Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn first_packet_with_the_key_authenticates() {
+        let mut session = session(SessionState::Connecting);
+        assert_eq!(session.heard_from(), Some(SessionState::Connecting));
+        assert_eq!(session.state, SessionState::Authenticated);
+        assert_eq!(session.heard_from(), None);
+    }
+
+    #[test]
+    fn sessions_that_stop_pinging_time_out_on_their_level() {
+        let mut session = session(SessionState::InLevel((1, 0)));
+        assert_eq!(session.check_timeouts(&config()), Timeout::None);
+
+        session.last_ping = ago(60);
+        assert_eq!(session.check_timeouts(&config()), Timeout::TimedOut);
+        assert_eq!(session.state, SessionState::TimedOut { level: Some((1, 0)) });
+        assert!(!session.is_open());
+        assert_eq!(session.level(), Some((1, 0)));
+
+        // only once
+        assert_eq!(session.check_timeouts(&config()), Timeout::None);
+    }
+
+    #[test]
+    fn players_that_stop_moving_are_idle() {
+        let mut in_level = session(SessionState::InLevel((1, 0)));
+        in_level.last_position = ago(600);
+        assert_eq!(in_level.check_timeouts(&config()), Timeout::Idle);
+
+        // not on a level, nothing to take them off
+        let mut authenticated = session(SessionState::Authenticated);
+        authenticated.last_position = ago(600);
+        assert_eq!(authenticated.check_timeouts(&config()), Timeout::None);
+    }
+
+    #[test]
+    fn timed_out_sessions_resume_where_they_were() {
+        let mut session = session(SessionState::TimedOut { level: Some((1, 0)) });
+        session.last_ping = ago(70);
+        session.last_position = ago(70);
+
+        assert_eq!(session.heard_from(), Some(SessionState::TimedOut { level: Some((1, 0)) }));
+        assert_eq!(session.state, SessionState::InLevel((1, 0)));
+        assert_eq!(session.check_timeouts(&config()), Timeout::None);
+    }
+
+    #[test]
+    fn timed_out_sessions_expire_after_the_grace_period() {
+        let mut session = session(SessionState::TimedOut { level: Some((1, 0)) });
+        session.last_ping = ago(80);
+        assert_eq!(session.check_timeouts(&config()), Timeout::None);
+
+        session.last_ping = ago(90);
+        assert_eq!(session.check_timeouts(&config()), Timeout::Expired);
+    }
+
+    #[test]
+    fn idle_timeout_can_be_turned_off() {
+        let config = Config {
+            idle_timeout: Duration::ZERO,
+            ..config()
+        };
+        let mut session = session(SessionState::InLevel((1, 0)));
+        session.last_position = ago(100_000);
+        assert_eq!(session.check_timeouts(&config), Timeout::None);
+    }
+
+    fn sessions_with(state: SessionState, features: u32) -> Sessions {
+        let mut sessions = Sessions::default();
+        sessions.clients.insert(
+            7,
+            Session {
+                features,
+                ..session(state)
+            },
+        );
+        sessions
+    }
+
+    #[test]
+    fn hello_with_the_key_resumes_on_the_level() {
+        let mut sessions = sessions_with(SessionState::TimedOut { level: Some((1, 0)) }, 0);
+        let address = sessions.clients[&7].address;
+
+        // stock clients keep their own key, so this is what they look like coming back
+        assert!(!sessions.is_stale(7, address, 1));
+        assert_eq!(sessions.hello(7, address, 1, 1, 0), HelloOutcome::Accepted { resumed: true });
+        assert_eq!(sessions.clients[&7].state, SessionState::InLevel((1, 0)));
+
+        assert_eq!(sessions.hello(8, address, 5, 5, 0), HelloOutcome::Accepted { resumed: false });
+        assert_eq!(sessions.clients[&8].state, SessionState::Connecting);
+    }
+
+    #[test]
+    fn hello_without_the_key_is_refused() {
+        let mut sessions = sessions_with(SessionState::InLevel((1, 0)), 0);
+        let address = sessions.clients[&7].address;
+
+        assert!(!sessions.is_stale(7, address, 2));
+        assert_eq!(sessions.hello(7, address, 2, 2, 0), HelloOutcome::InUse);
+        assert_eq!(sessions.clients[&7].key, 1);
+
+        // unless the session is timed out, then it's replaced
+        sessions.clients.get_mut(&7).unwrap().state = SessionState::TimedOut { level: Some((1, 0)) };
+        assert!(sessions.is_stale(7, address, 2));
+    }
+
+    #[test]
+    fn hello_from_a_new_address_has_to_roam() {
+        let elsewhere = SocketAddr::from(([10, 0, 0, 2], 1234));
+
+        let mut sessions = sessions_with(SessionState::TimedOut { level: Some((1, 0)) }, FEATURE_SESSION_KEYS);
+        assert!(!sessions.is_stale(7, elsewhere, 1));
+        assert!(matches!(sessions.hello(7, elsewhere, 1, 1, FEATURE_SESSION_KEYS), HelloOutcome::Roam(_)));
+        assert_ne!(sessions.clients[&7].address, elsewhere);
+
+        // stock clients can't answer the challenge, they start over instead
+        let sessions = sessions_with(SessionState::TimedOut { level: Some((1, 0)) }, 0);
+        assert!(sessions.is_stale(7, elsewhere, 1));
+    }
+}
@@ -0,0 +1,36 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Server-wide counters, shared without going through the state lock.
+#[derive(Default)]
+pub struct Stats {
+    pub packets_received: AtomicU64,
+    pub rate_limited: AtomicU64,      // dropped for going over a token bucket
+    pub dropped_banned: AtomicU64,    // dropped because the address was temp-banned
+    pub bans: AtomicU64,
+    pub queue_full: AtomicU64,        // dropped because the client's packets weren't being handled fast enough
+    pub send_failed: AtomicU64,       // the socket refused to send a datagram
+    pub send_dropped: AtomicU64,      // not sent, too much was already queued for the recipient
+    pub outbound_full: AtomicU64,     // not sent, the send queue was full
+    pub unknown_recipient: AtomicU64, // not sent, the client was gone by the time it was sent to
+}
+
+impl Stats {
+    pub fn bump(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn to_json(&self) -> String {
+        format!(
+            r#"{{"packets_received":{},"rate_limited":{},"dropped_banned":{},"bans":{},"queue_full":{},"send_failed":{},"send_dropped":{},"outbound_full":{},"unknown_recipient":{}}}"#,
+            self.packets_received.load(Ordering::Relaxed),
+            self.rate_limited.load(Ordering::Relaxed),
+            self.dropped_banned.load(Ordering::Relaxed),
+            self.bans.load(Ordering::Relaxed),
+            self.queue_full.load(Ordering::Relaxed),
+            self.send_failed.load(Ordering::Relaxed),
+            self.send_dropped.load(Ordering::Relaxed),
+            self.outbound_full.load(Ordering::Relaxed),
+            self.unknown_recipient.load(Ordering::Relaxed),
+        )
+    }
+}
@@ -0,0 +1,258 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use http_body_util::{BodyExt, Empty};
+use hyper::{body::Bytes, client::conn::http1::SendRequest, Request, StatusCode, Uri};
+use hyper_util::rt::TokioIo;
+use log::{debug, info, warn};
+use tokio::{net::TcpStream, time::timeout};
+
+// idle keep-alive connections kept per upstream
+const MAX_IDLE_CONNECTIONS: usize = 8;
+// consecutive failures before an upstream is skipped, and for how long
+const BREAKER_THRESHOLD: u32 = 3;
+const BREAKER_COOLDOWN: Duration = Duration::from_secs(30);
+
+// only the upstream's own failures count towards its breaker, not requests it turned down
+enum FetchError {
+    Down(anyhow::Error), // couldn't connect, timed out or answered with a 5xx
+    Refused(StatusCode), // answered, but not with an icon
+}
+
+#[derive(Default)]
+struct Breaker {
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+struct Upstream {
+    url: Uri,
+    address: String,
+    authority: String,
+    idle: Mutex<Vec<SendRequest<Empty<Bytes>>>>,
+    breaker: Mutex<Breaker>,
+}
+
+impl Upstream {
+    fn take_idle(&self) -> Option<SendRequest<Empty<Bytes>>> {
+        let mut idle = self.idle.lock().unwrap();
+        while let Some(sender) = idle.pop() {
+            if !sender.is_closed() {
+                return Some(sender);
+            }
+        }
+        None
+    }
+
+    fn put_idle(&self, sender: SendRequest<Empty<Bytes>>) {
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < MAX_IDLE_CONNECTIONS && !sender.is_closed() {
+            idle.push(sender);
+        }
+    }
+
+    fn is_available(&self) -> bool {
+        let breaker = self.breaker.lock().unwrap();
+        !matches!(breaker.open_until, Some(until) if Instant::now() < until)
+    }
+
+    fn record_success(&self) {
+        let mut breaker = self.breaker.lock().unwrap();
+        if breaker.open_until.is_some() {
+            info!("icon upstream {} is back up", self.url);
+        }
+        *breaker = Breaker::default();
+    }
+
+    fn record_failure(&self) {
+        let mut breaker = self.breaker.lock().unwrap();
+        breaker.failures += 1;
+        if breaker.failures >= BREAKER_THRESHOLD {
+            warn!(
+                "icon upstream {} failed {} times in a row, skipping it for {}s",
+                self.url,
+                breaker.failures,
+                BREAKER_COOLDOWN.as_secs()
+            );
+            breaker.open_until = Some(Instant::now() + BREAKER_COOLDOWN);
+        }
+
+        // a dead upstream most likely killed the pooled connections too
+        self.idle.lock().unwrap().clear();
+    }
+}
+
+/// Upstream `getIcon.php` servers, tried in order until one of them answers.
+/// Connections are kept alive and reused, and upstreams that keep failing are skipped for a while.
+pub struct UpstreamPool {
+    upstreams: Vec<Upstream>,
+    connect_timeout: Duration,
+    read_timeout: Duration,
+}
+
+impl UpstreamPool {
+    pub fn new(urls: &[String], connect_timeout: Duration, read_timeout: Duration) -> anyhow::Result<Self> {
+        let mut upstreams = vec![];
+        for url in urls.iter() {
+            let url = url.parse::<Uri>()?;
+            let host = url.host().ok_or(anyhow!("upstream {url} has no host"))?;
+            let port = url.port_u16().unwrap_or(80);
+
+            upstreams.push(Upstream {
+                address: format!("{host}:{port}"),
+                authority: url.authority().map(|a| a.to_string()).unwrap_or(host.to_string()),
+                url,
+                idle: Mutex::new(vec![]),
+                breaker: Mutex::new(Breaker::default()),
+            });
+        }
+
+        Ok(UpstreamPool {
+            upstreams,
+            connect_timeout,
+            read_timeout,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.upstreams.is_empty()
+    }
+
+    /// Returns `None` if no upstream had the icon, because they're down or don't know it.
+    /// `query` has to be encoded already, see `query_string`.
+    pub async fn fetch(&self, query: &str) -> Option<Vec<u8>> {
+        for upstream in self.upstreams.iter() {
+            if !upstream.is_available() {
+                continue;
+            }
+
+            // built before a connection is taken, a request that can't be made isn't the upstream's fault
+            let req = match self.request(upstream, query) {
+                Ok(req) => req,
+                Err(e) => {
+                    warn!("can't ask icon upstream {} for {query:?}: {e}", upstream.url);
+                    continue;
+                }
+            };
+
+            match self.fetch_from(upstream, req).await {
+                Ok(body) => {
+                    upstream.record_success();
+                    return Some(body);
+                }
+                Err(FetchError::Refused(status)) => {
+                    debug!("icon upstream {} returned {status:?}", upstream.url);
+                    upstream.record_success();
+                }
+                Err(FetchError::Down(e)) => {
+                    warn!("icon upstream {} failed: {e}", upstream.url);
+                    upstream.record_failure();
+                }
+            }
+        }
+
+        None
+    }
+
+    fn request(&self, upstream: &Upstream, query: &str) -> anyhow::Result<Request<Empty<Bytes>>> {
+        let uri = format!("{}?{query}", upstream.url).parse::<Uri>()?;
+        let req = Request::builder()
+            .uri(uri)
+            .header(hyper::header::HOST, upstream.authority.as_str())
+            .body(Empty::<Bytes>::new())?;
+        Ok(req)
+    }
+
+    async fn fetch_from(&self, upstream: &Upstream, req: Request<Empty<Bytes>>) -> Result<Vec<u8>, FetchError> {
+        let mut sender = match upstream.take_idle() {
+            Some(sender) => sender,
+            None => self.connect(upstream).await.map_err(FetchError::Down)?,
+        };
+
+        let response = async {
+            sender.ready().await?;
+            let mut res = sender.send_request(req).await?;
+
+            let status = res.status();
+            if !status.is_success() {
+                return Ok(Err(status));
+            }
+
+            let mut body = vec![];
+            while let Some(next) = res.frame().await {
+                let frame = next?;
+                if let Some(chunk) = frame.data_ref() {
+                    body.extend_from_slice(chunk);
+                }
+            }
+
+            Ok::<_, anyhow::Error>(Ok(body))
+        };
+
+        let result = timeout(self.read_timeout, response)
+            .await
+            .map_err(|_| FetchError::Down(anyhow!("timed out reading the response")))?
+            .map_err(FetchError::Down)?;
+
+        match result {
+            Ok(body) => {
+                upstream.put_idle(sender);
+                Ok(body)
+            }
+            Err(status) if status.is_server_error() => Err(FetchError::Down(anyhow!("returned {status:?}"))),
+            Err(status) => Err(FetchError::Refused(status)),
+        }
+    }
+
+    async fn connect(&self, upstream: &Upstream) -> anyhow::Result<SendRequest<Empty<Bytes>>> {
+        debug!("opening a new connection to {}", upstream.address);
+
+        let stream = timeout(self.connect_timeout, TcpStream::connect(&upstream.address))
+            .await
+            .map_err(|_| anyhow!("timed out connecting"))??;
+        let io = TokioIo::new(stream);
+
+        let (sender, conn) = hyper::client::conn::http1::handshake(io).await?;
+        tokio::task::spawn(async move {
+            if let Err(err) = conn.await {
+                warn!("Connection failed: {:?}", err);
+            }
+        });
+
+        Ok(sender)
+    }
+}
+
+/// Builds a query string, percent-encoding everything but the characters URLs allow as they are.
+pub fn query_string(params: &[(&str, &str)]) -> String {
+    let encode = |value: &str| {
+        value
+            .bytes()
+            .map(|byte| match byte {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (byte as char).to_string(),
+                _ => format!("%{byte:02X}"),
+            })
+            .collect::<String>()
+    };
+
+    params
+        .iter()
+        .map(|(name, value)| format!("{}={}", encode(name), encode(value)))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn query_values_are_encoded() {
+        let query = query_string(&[("form", "cube"), ("id", "a b&c=d"), ("glow", "ü")]);
+        assert_eq!(query, "form=cube&id=a%20b%26c%3Dd&glow=%C3%BC");
+        assert!(format!("http://example.com/getIcon.php?{query}").parse::<Uri>().is_ok());
+    }
+}
@@ -0,0 +1,103 @@
+use std::{collections::BTreeSet, io::ErrorKind, path::PathBuf, time::SystemTime};
+
+use log::{info, warn};
+use tokio::fs;
+
+/// VIP players, stored as a plain text file with one player ID per line. Lines starting with `#` are ignored.
+/// The file is picked up again when it changes on disk, so operators can also edit it by hand.
+pub struct VipRegistry {
+    path: PathBuf,
+    players: BTreeSet<i32>,
+    modified: Option<SystemTime>,
+}
+
+impl VipRegistry {
+    pub async fn load(path: PathBuf) -> anyhow::Result<Self> {
+        let mut registry = VipRegistry {
+            path,
+            players: BTreeSet::new(),
+            modified: None,
+        };
+
+        registry.reload().await?;
+        info!("loaded {} VIP players from {:?}", registry.players.len(), registry.path);
+        Ok(registry)
+    }
+
+    pub fn is_vip(&self, player_id: i32) -> bool {
+        self.players.contains(&player_id)
+    }
+
+    pub async fn grant(&mut self, player_id: i32) -> anyhow::Result<()> {
+        if self.players.insert(player_id) {
+            info!("granted VIP to {player_id}");
+            self.save().await?;
+        }
+        Ok(())
+    }
+
+    pub async fn revoke(&mut self, player_id: i32) -> anyhow::Result<()> {
+        if self.players.remove(&player_id) {
+            info!("revoked VIP from {player_id}");
+            self.save().await?;
+        }
+        Ok(())
+    }
+
+    pub async fn reload_if_changed(&mut self) -> anyhow::Result<()> {
+        let modified = match fs::metadata(&self.path).await {
+            Ok(meta) => Some(meta.modified()?),
+            Err(e) if e.kind() == ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+
+        if modified != self.modified {
+            self.reload().await?;
+            info!("VIP list changed on disk, now {} players", self.players.len());
+        }
+
+        Ok(())
+    }
+
+    async fn reload(&mut self) -> anyhow::Result<()> {
+        let contents = match fs::read_to_string(&self.path).await {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        self.players = contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(|line| match line.parse::<i32>() {
+                Ok(id) => Some(id),
+                Err(_) => {
+                    warn!("ignoring invalid VIP entry {line:?}");
+                    None
+                }
+            })
+            .collect();
+
+        self.modified = fs::metadata(&self.path)
+            .await
+            .ok()
+            .and_then(|meta| meta.modified().ok());
+
+        Ok(())
+    }
+
+    async fn save(&mut self) -> anyhow::Result<()> {
+        let mut contents = String::from("# VIP player IDs, one per line\n");
+        for id in self.players.iter() {
+            contents.push_str(&format!("{id}\n"));
+        }
+
+        // written next to the list and moved over it, so a crash halfway through can't leave it cut off
+        let temp = self.path.with_extension("tmp");
+        fs::write(&temp, contents).await?;
+        fs::rename(&temp, &self.path).await?;
+        self.modified = fs::metadata(&self.path).await?.modified().ok();
+        Ok(())
+    }
+}